@@ -0,0 +1,323 @@
+use crate::mapping::HOME_PLACEHOLDER;
+use crate::restore::{RestoreChange, RestoreContent, RestorePoint};
+use crate::storage;
+use crate::temp::TempDir;
+use crate::{BackupContext, Configuration};
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 归档中manifest的文件名
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+/// 归档中保存文件内容的目录，文件名为内容的sha256
+const FILES_DIR: &str = "files";
+
+/// 没有备份文件可参考时使用的权限
+const DEFAULT_MODE: u32 = 0o644;
+
+/// 导出的归档的描述
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    /// 导出时的revision
+    pub revision: String,
+    /// 导出时间，unix时间戳
+    pub time: u64,
+    #[serde(default, rename = "file")]
+    pub files: Vec<ArchiveFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub configuration: String,
+    /// 源文件路径，`$HOME`下的文件以`~/`开头，导入时替换为当前的`$HOME`
+    pub path: PathBuf,
+    /// revision中记录的权限，导入时只使用0o777权限位
+    pub mode: u32,
+    pub sha256: String,
+}
+
+impl BackupContext {
+    /// 将names中的configuration(为空时为所有)在point(默认最新)时的备份导出到archive_path
+    ///
+    /// 归档为`tar.gz`格式，包含[MANIFEST_FILE]与`files/<sha256>`
+    pub fn export(
+        &self,
+        names: &[String],
+        point: Option<&RestorePoint>,
+        archive_path: &Path,
+    ) -> io::Result<ArchiveManifest> {
+        let revision = match point {
+            Some(point) => self.resolve_restore_point(point)?,
+            None => self.storage.resolve("HEAD")?,
+        };
+        let configs = self.get_export_configurations(names)?;
+
+        let stage = TempDir::new("export")?;
+        let stage = stage.path();
+        self.export_to(&configs, &revision, stage)
+            .and_then(|manifest| {
+                tar(&[
+                    "-czf".as_ref(),
+                    archive_path.as_os_str(),
+                    "-C".as_ref(),
+                    stage.as_os_str(),
+                    ".".as_ref(),
+                ])?;
+                Ok(manifest)
+            })
+    }
+
+    /// 通过restore将archive_path中的文件恢复到当前机器，dry_run时只返回将发生的修改
+    ///
+    /// 文件内容的sha256与manifest不一致时返回ErrorKind::InvalidData，不会修改任何文件
+    pub fn import(&self, archive_path: &Path, dry_run: bool) -> io::Result<Vec<RestoreChange>> {
+        let stage = TempDir::new("import")?;
+        tar(&[
+            "-xzf".as_ref(),
+            archive_path.as_os_str(),
+            "-C".as_ref(),
+            stage.path().as_os_str(),
+        ])?;
+        let (manifest, contents) = read_archive(stage.path())?;
+        self.restore_contents(contents, &manifest.revision, dry_run)
+    }
+
+    fn get_export_configurations(&self, names: &[String]) -> io::Result<Vec<&Configuration>> {
+        if names.is_empty() {
+            return Ok(self.configurations.iter().collect());
+        }
+        names
+            .iter()
+            .map(|name| {
+                self.configurations
+                    .iter()
+                    .find(|config| &config.name == name)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("configuration: {} 不存在", name),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    fn export_to(
+        &self,
+        configs: &[&Configuration],
+        revision: &str,
+        stage: &Path,
+    ) -> io::Result<ArchiveManifest> {
+        let files_dir = stage.join(FILES_DIR);
+        fs::create_dir_all(&files_dir)?;
+        let mut files = vec![];
+        for config in configs {
+            for (origin_path, rel_path) in self.list_configuration_files(config, revision)? {
+                let content = self.storage.show(revision, &rel_path)?;
+                let sha256 = storage::hash_content(&content);
+                fs::write(files_dir.join(&sha256), &content)?;
+                files.push(ArchiveFile {
+                    configuration: config.name.clone(),
+                    mode: self.get_export_mode(revision, &rel_path),
+                    path: to_archive_path(&origin_path),
+                    sha256,
+                });
+            }
+        }
+        let manifest = ArchiveManifest {
+            revision: revision.to_string(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs(),
+            files,
+        };
+        let content = toml::to_string(&manifest).map_err(io::Error::other)?;
+        fs::write(stage.join(MANIFEST_FILE), content)?;
+        Ok(manifest)
+    }
+
+    /// 导出的revision中记录的权限，而不是备份目录中当前文件的权限
+    fn get_export_mode(&self, revision: &str, rel_path: &Path) -> u32 {
+        self.storage
+            .get_mode(revision, rel_path)
+            .map(|mode| mode & 0o777)
+            .unwrap_or(DEFAULT_MODE)
+    }
+}
+
+/// 读取解压到dir的归档并校验所有文件的sha256
+fn read_archive(dir: &Path) -> io::Result<(ArchiveManifest, Vec<RestoreContent>)> {
+    let manifest = fs::read_to_string(dir.join(MANIFEST_FILE))?;
+    let manifest: ArchiveManifest =
+        toml::from_str(&manifest).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut contents = vec![];
+    for file in &manifest.files {
+        let content = fs::read(dir.join(FILES_DIR).join(&file.sha256))?;
+        if storage::hash_content(&content) != file.sha256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("path: {} 的sha256与manifest不一致", file.path.display()),
+            ));
+        }
+        contents.push(RestoreContent {
+            path: from_archive_path(&file.path)?,
+            content,
+            // 不恢复归档中的setuid、setgid与sticky位
+            mode: Some(file.mode & 0o777),
+        });
+    }
+    Ok((manifest, contents))
+}
+
+/// 将`$HOME`下的路径替换为`~/`开头
+fn to_archive_path(path: &Path) -> PathBuf {
+    match env::var_os("HOME").and_then(|home| path.strip_prefix(home).ok().map(Path::to_path_buf)) {
+        Some(rel) => Path::new(HOME_PLACEHOLDER).join(rel),
+        None => path.to_path_buf(),
+    }
+}
+
+fn from_archive_path(path: &Path) -> io::Result<PathBuf> {
+    match path.strip_prefix(HOME_PLACEHOLDER) {
+        Ok(rel) => {
+            let home = env::var_os("HOME")
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "环境变量HOME不存在"))?;
+            Ok(PathBuf::from(home).join(rel))
+        }
+        Err(_) if path.is_absolute() => Ok(path.to_path_buf()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("非法的路径: {}", path.display()),
+        )),
+    }
+}
+
+fn tar(args: &[&std::ffi::OsStr]) -> io::Result<()> {
+    let out = Command::new("tar").args(args).output()?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "tar error: code={:?}, {}",
+            out.status.code(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn backup_context(backup: &Path, src: &Path) -> BackupContext {
+        BackupContext::for_test(vec![Configuration::for_test("test", &[src])], backup)
+    }
+
+    #[test]
+    fn export_and_import() {
+        let dir = env::temp_dir().join("auto_configuration_archive");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let context = backup_context(&dir.join("backup"), &src);
+        let a = src.join("a.sh");
+        fs::write(&a, "1\n").unwrap();
+        fs::set_permissions(&a, fs::Permissions::from_mode(0o750)).unwrap();
+        context.hold(&a).unwrap();
+        context.commit(&a).unwrap();
+
+        let archive = dir.join("backup.tar.gz");
+        let manifest = context.export(&[], None, &archive).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        // git只记录可执行位
+        assert_eq!(manifest.files[0].mode, 0o755);
+        assert_eq!(manifest.files[0].sha256, storage::hash_content(b"1\n"));
+        assert!(context
+            .export(&["none".to_string()], None, &archive)
+            .is_err());
+
+        // 在另一个备份目录中导入
+        fs::remove_file(&a).unwrap();
+        let other = backup_context(&dir.join("other"), &src);
+        let changes = other.import(&archive, true).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].revision, manifest.revision);
+        assert!(!a.exists());
+        other.import(&archive, false).unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "1\n");
+        assert_eq!(
+            fs::metadata(&a).unwrap().permissions().mode() & 0o7777,
+            0o755
+        );
+        assert!(other.import(&archive, false).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_modified_archive() {
+        let dir = env::temp_dir().join("auto_configuration_archive_modified");
+        let _ = fs::remove_dir_all(&dir);
+        let files = dir.join(FILES_DIR);
+        fs::create_dir_all(&files).unwrap();
+        let manifest = ArchiveManifest {
+            revision: "HEAD".to_string(),
+            time: 0,
+            files: vec![ArchiveFile {
+                configuration: "test".to_string(),
+                path: PathBuf::from("/tmp/a"),
+                mode: DEFAULT_MODE,
+                sha256: storage::hash_content(b"a"),
+            }],
+        };
+        fs::write(dir.join(MANIFEST_FILE), toml::to_string(&manifest).unwrap()).unwrap();
+        fs::write(files.join(&manifest.files[0].sha256), "b").unwrap();
+        assert_eq!(
+            read_archive(&dir).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mask_archive_mode() {
+        let dir = env::temp_dir().join("auto_configuration_archive_setuid");
+        let _ = fs::remove_dir_all(&dir);
+        let files = dir.join(FILES_DIR);
+        fs::create_dir_all(&files).unwrap();
+        let manifest = ArchiveManifest {
+            revision: "HEAD".to_string(),
+            time: 0,
+            files: vec![ArchiveFile {
+                configuration: "test".to_string(),
+                path: PathBuf::from("/tmp/a"),
+                mode: 0o4755,
+                sha256: storage::hash_content(b"a"),
+            }],
+        };
+        fs::write(dir.join(MANIFEST_FILE), toml::to_string(&manifest).unwrap()).unwrap();
+        fs::write(files.join(&manifest.files[0].sha256), "a").unwrap();
+        let (_, contents) = read_archive(&dir).unwrap();
+        assert_eq!(contents[0].mode, Some(0o755));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn archive_path_with_home() {
+        let home = PathBuf::from(env::var("HOME").unwrap());
+        let path = to_archive_path(&home.join(".zshrc"));
+        assert_eq!(path, PathBuf::from("~/.zshrc"));
+        assert_eq!(from_archive_path(&path).unwrap(), home.join(".zshrc"));
+        assert_eq!(
+            from_archive_path(Path::new("/etc/my.cnf")).unwrap(),
+            PathBuf::from("/etc/my.cnf")
+        );
+        assert!(from_archive_path(Path::new("etc/my.cnf")).is_err());
+    }
+}
@@ -12,61 +12,423 @@ use std::process::*;
 extern crate regex;
 
 use regex::Regex;
+use serde::Deserialize;
+
+use crate::package;
 
 #[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum PackageManager {
     AptGet,
     Pacman,
+    Dnf,
+    Zypper,
+    Apk,
+    /// `cargo install`，以下为用户级的包管理器
+    Cargo,
+    Pipx,
+    /// `npm -g`
+    Npm,
     Other,
 }
 
+/// 卸载时的选项
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UninstallOptions {
+    /// 同时删除包的配置文件，如`apt-get purge`
+    pub purge: bool,
+    /// 同时删除不再被依赖的包
+    pub remove_orphans: bool,
+}
+
+/// 系统包管理器需要root权限时的提权方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrivilegeStrategy {
+    /// 依次使用存在的`sudo -n`、pkexec，不会等待输入sudo的密码，
+    /// 需要输入密码时使用Sudo
+    #[default]
+    Auto,
+    Sudo,
+    /// `sudo -n`，需要密码时失败而不是等待输入
+    #[serde(rename = "sudo-n")]
+    SudoNonInteractive,
+    Pkexec,
+    /// 不提权，不是root时返回错误
+    Refuse,
+}
+
+impl PrivilegeStrategy {
+    /// 提权命令的前缀，root时为空；需要提权但不可用时返回ErrorKind::PermissionDenied
+    fn get_prefix(
+        &self,
+        root: bool,
+        exists: impl Fn(&str) -> bool,
+    ) -> io::Result<Vec<&'static str>> {
+        if root {
+            return Ok(vec![]);
+        }
+        let prefix = match self {
+            PrivilegeStrategy::Auto if exists("sudo") => vec!["sudo", "-n"],
+            PrivilegeStrategy::Auto if exists("pkexec") => vec!["pkexec"],
+            PrivilegeStrategy::Auto => {
+                return Err(permission_denied("sudo与pkexec都不存在".to_string()))
+            }
+            PrivilegeStrategy::Sudo => vec!["sudo"],
+            PrivilegeStrategy::SudoNonInteractive => vec!["sudo", "-n"],
+            PrivilegeStrategy::Pkexec => vec!["pkexec"],
+            PrivilegeStrategy::Refuse => {
+                return Err(permission_denied("privilege为refuse".to_string()))
+            }
+        };
+        if exists(prefix[0]) {
+            Ok(prefix)
+        } else {
+            Err(permission_denied(format!("{}不存在", prefix[0])))
+        }
+    }
+
+    /// dry-run显示的提权前缀，不检查命令是否存在：Auto显示为`sudo -n`，Refuse不提权
+    fn describe_prefix(&self, root: bool) -> Vec<&'static str> {
+        match self {
+            _ if root => vec![],
+            PrivilegeStrategy::Auto | PrivilegeStrategy::SudoNonInteractive => vec!["sudo", "-n"],
+            PrivilegeStrategy::Sudo => vec!["sudo"],
+            PrivilegeStrategy::Pkexec => vec!["pkexec"],
+            PrivilegeStrategy::Refuse => vec![],
+        }
+    }
+}
+
+fn permission_denied(reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("安装系统包需要root权限，但{}", reason),
+    )
+}
+
+/// 查找包管理器与提权命令的位置，测试时指向fake命令所在的目录，不需要修改PATH
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandLookup<'a> {
+    /// None时从PATH中查找
+    bin_dir: Option<&'a Path>,
+    /// 为true时不提权
+    root: bool,
+}
+
+impl CommandLookup<'static> {
+    /// 从PATH中查找，按当前进程的uid判断是否提权
+    pub(crate) fn system() -> Self {
+        CommandLookup {
+            bin_dir: None,
+            root: is_root(),
+        }
+    }
+}
+
+impl<'a> CommandLookup<'a> {
+    pub(crate) fn command(&self, name: &str) -> Command {
+        match self.bin_dir {
+            Some(dir) => Command::new(dir.join(name)),
+            None => Command::new(name),
+        }
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        match self.bin_dir {
+            Some(dir) => dir.join(name).is_file(),
+            None => package::command_exists(name),
+        }
+    }
+}
+
+/// 当前进程的有效uid是否为0
+fn is_root() -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let uids = status.lines().find_map(|line| line.strip_prefix("Uid:"))?;
+            uids.split_whitespace().nth(1).map(|euid| euid == "0")
+        })
+        .unwrap_or(false)
+}
+
 #[allow(unused)]
 impl PackageManager {
-    pub fn install(&self, name: &str) -> io::Result<()> {
+    pub fn install(&self, name: &str, privilege: PrivilegeStrategy) -> io::Result<()> {
+        self.install_multiple(vec![name], privilege)
+    }
+
+    /// 以非交互方式安装，输出被捕获，失败时包含在错误中
+    pub fn install_multiple(
+        &self,
+        names: Vec<&str>,
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<()> {
+        self.install_with(CommandLookup::system(), names, privilege)
+    }
+
+    fn install_with(
+        &self,
+        lookup: CommandLookup,
+        names: Vec<&str>,
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<()> {
         match self {
-            AptGet => Command::new("sudo")
-                .arg("apt-get")
-                .arg("install")
-                .arg(name)
-                .spawn()?
-                .wait()
-                .and_then(|status| {
-                    if status.success() {
-                        Ok(())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("未知错误: code={}", status.code().unwrap()),
-                        ))
-                    }
-                }),
-            _ => panic!("unsupported!"),
+            PackageManager::Other => Err(unsupported_manager()),
+            _ => run_captured(
+                self.command(lookup, &self.get_install_args(), privilege)?
+                    .args(names),
+            ),
         }
     }
 
-    pub fn install_multiple(&self, names: Vec<&str>) -> io::Result<()> {
+    /// install_multiple将执行的命令，不执行，
+    /// 也不检查提权命令是否可用，见[PrivilegeStrategy::describe_prefix]
+    pub fn describe_install(
+        &self,
+        names: &[String],
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<String> {
+        let lookup = CommandLookup::system();
+        let prefix = if self.is_system() {
+            privilege.describe_prefix(lookup.root)
+        } else {
+            vec![]
+        };
         match self {
-            AptGet => Command::new("apt-get")
-                .arg("install")
-                .args(names)
-                .spawn()?
-                .wait()
-                .and_then(|status| {
-                    if status.success() {
-                        Ok(())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("未知错误: code={}", status.code().unwrap()),
-                        ))
-                    }
-                }),
-            _ => panic!("unsupported!"),
+            PackageManager::Other => Err(unsupported_manager()),
+            _ => Ok(describe_command(
+                self.build_command(lookup, &prefix, &self.get_install_args())
+                    .args(names),
+            )),
+        }
+    }
+
+    pub fn uninstall(
+        &self,
+        name: &str,
+        options: UninstallOptions,
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<()> {
+        self.uninstall_multiple(vec![name], options, privilege)
+    }
+
+    /// purge只作用于names，remove_orphans移除的依赖不purge，它们的配置文件没有备份
+    pub fn uninstall_multiple(
+        &self,
+        names: Vec<&str>,
+        options: UninstallOptions,
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<()> {
+        self.uninstall_with(CommandLookup::system(), names, options, privilege)
+    }
+
+    fn uninstall_with(
+        &self,
+        lookup: CommandLookup,
+        names: Vec<&str>,
+        options: UninstallOptions,
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<()> {
+        if *self == PackageManager::Other {
+            return Err(unsupported_manager());
+        }
+        run_captured(
+            self.command(lookup, &self.get_uninstall_args(options), privilege)?
+                .args(names),
+        )?;
+        if !(options.purge && options.remove_orphans) {
+            return Ok(());
+        }
+        // 与purge在同一个命令中时依赖也会被purge，之后单独移除
+        match self {
+            PackageManager::AptGet => {
+                run_captured(&mut self.command(lookup, &["autoremove", "-y"], privilege)?)
+            }
+            PackageManager::Pacman => {
+                let orphans = self.list_orphans_with(lookup)?;
+                if orphans.is_empty() {
+                    return Ok(());
+                }
+                run_captured(
+                    self.command(lookup, &["-Rs", "--noconfirm"], privilege)?
+                        .args(orphans),
+                )
+            }
+            _ => Ok(()),
         }
     }
 
-    pub fn uninstall(&self, name: &str) -> io::Result<()> {
+    /// 包name是否已安装
+    pub fn is_installed(&self, name: &str) -> io::Result<bool> {
+        self.installed_version_with(CommandLookup::system(), name)
+            .map(|version| version.is_some())
+    }
+
+    /// 是否为安装到系统(需要root)的包管理器
+    pub fn is_system(&self) -> bool {
+        matches!(
+            self,
+            PackageManager::AptGet
+                | PackageManager::Pacman
+                | PackageManager::Dnf
+                | PackageManager::Zypper
+                | PackageManager::Apk
+        )
+    }
+
+    /// 命令名，也是configuration.toml中的名称
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            PackageManager::AptGet => "apt-get",
+            PackageManager::Pacman => "pacman",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Zypper => "zypper",
+            PackageManager::Apk => "apk",
+            PackageManager::Cargo => "cargo",
+            PackageManager::Pipx => "pipx",
+            PackageManager::Npm => "npm",
+            PackageManager::Other => "other",
+        }
+    }
+
+    /// 系统包管理器不是root时按privilege提权
+    fn command(
+        &self,
+        lookup: CommandLookup,
+        args: &[&str],
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<Command> {
+        let prefix = if self.is_system() {
+            privilege.get_prefix(lookup.root, |name| lookup.exists(name))?
+        } else {
+            vec![]
+        };
+        Ok(self.build_command(lookup, &prefix, args))
+    }
+
+    /// 有提权前缀时环境变量通过`env`传递，sudo与pkexec会清除环境变量
+    fn build_command(&self, lookup: CommandLookup, prefix: &[&str], args: &[&str]) -> Command {
+        let envs = self.get_envs();
+        let mut command = match prefix.split_first() {
+            Some((program, rest)) => {
+                let mut command = lookup.command(program);
+                command.args(rest);
+                if !envs.is_empty() {
+                    command.arg("env");
+                    command.args(envs.iter().map(|(key, value)| format!("{}={}", key, value)));
+                }
+                command.arg(self.get_name());
+                command
+            }
+            None => {
+                let mut command = lookup.command(self.get_name());
+                command.envs(envs.iter().copied());
+                command
+            }
+        };
+        command.args(args);
+        command
+    }
+
+    /// 使包管理器不交互的环境变量
+    fn get_envs(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            PackageManager::AptGet => vec![("DEBIAN_FRONTEND", "noninteractive")],
+            _ => vec![],
+        }
+    }
+
+    /// 安装的命令参数，之后为包名
+    fn get_install_args(&self) -> Vec<&'static str> {
+        match self {
+            PackageManager::AptGet => vec!["install", "-y"],
+            // --needed跳过已安装且为最新的包
+            PackageManager::Pacman => vec!["-S", "--needed", "--noconfirm"],
+            PackageManager::Dnf => vec!["install", "-y"],
+            PackageManager::Zypper => vec!["--non-interactive", "install"],
+            PackageManager::Apk => vec!["add"],
+            PackageManager::Cargo => vec!["install"],
+            PackageManager::Pipx => vec!["install"],
+            PackageManager::Npm => vec!["install", "-g"],
+            PackageManager::Other => vec![],
+        }
+    }
+
+    /// 不支持的选项被忽略，如dnf总是保留修改过的配置为`.rpmsave`，
+    /// apk的`--purge`会同时作用于被移除的依赖，不使用。
+    /// purge时不在同一个命令中移除依赖，见[Self::uninstall_multiple]
+    fn get_uninstall_args(&self, options: UninstallOptions) -> Vec<&'static str> {
+        let mut args = match self {
+            PackageManager::AptGet if options.purge => vec!["purge", "-y"],
+            PackageManager::AptGet => vec!["remove", "-y"],
+            // n: 不保留.pacsave，s: 移除不再被依赖的包
+            PackageManager::Pacman => match (options.purge, options.remove_orphans) {
+                (true, _) => vec!["-Rn"],
+                (false, true) => vec!["-Rs"],
+                (false, false) => vec!["-R"],
+            },
+            PackageManager::Dnf => vec!["remove", "-y"],
+            PackageManager::Zypper => vec!["--non-interactive", "remove"],
+            PackageManager::Apk => vec!["del"],
+            PackageManager::Cargo => vec!["uninstall"],
+            PackageManager::Pipx => vec!["uninstall"],
+            PackageManager::Npm => vec!["uninstall", "-g"],
+            PackageManager::Other => vec![],
+        };
+        match self {
+            PackageManager::AptGet if options.remove_orphans && !options.purge => {
+                args.push("--autoremove")
+            }
+            PackageManager::Pacman => args.push("--noconfirm"),
+            PackageManager::Dnf if options.remove_orphans => {
+                args.push("--setopt=clean_requirements_on_remove=true")
+            }
+            PackageManager::Dnf => args.push("--setopt=clean_requirements_on_remove=false"),
+            PackageManager::Zypper if options.remove_orphans => args.push("--clean-deps"),
+            _ => {}
+        }
+        args
+    }
+}
+
+fn unsupported_manager() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "不支持的包管理器")
+}
+
+/// 以shell的形式显示command，如`DEBIAN_FRONTEND=noninteractive apt-get install -y zsh`
+fn describe_command(command: &Command) -> String {
+    let mut words = vec![];
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            words.push(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                value.to_string_lossy()
+            ));
+        }
+    }
+    words.push(command.get_program().to_string_lossy().to_string());
+    for arg in command.get_args() {
+        words.push(arg.to_string_lossy().to_string());
+    }
+    words.join(" ")
+}
+
+/// 执行command并捕获输出，退出码不为0时返回包含stderr的错误
+fn run_captured(command: &mut Command) -> io::Result<()> {
+    let out = command.stdin(Stdio::null()).output()?;
+    if out.status.success() {
         Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} 失败: code={:?}, {}",
+            command,
+            out.status.code(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )))
     }
 }
 
@@ -99,41 +461,6 @@ fn exec_in_dir(command: &str, dir: &str) -> Result<String, String> {
 
 /// -----------
 
-pub trait Program {
-    fn get_name(&self) -> &str;
-
-    fn get_package_manager(&self) -> &PackageManager;
-
-    fn install(&self) -> io::Result<()> {
-        if self.exists() {
-            Err(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                format!("{} 已安装", self.get_name()),
-            ))
-        } else {
-            self.get_package_manager().install(self.get_name())
-        }
-    }
-
-    fn uninstall(&self) -> io::Result<()> {
-        self.get_package_manager().uninstall(self.get_name())
-    }
-
-    fn config(&self) -> io::Result<()>;
-
-    /// 通过sh command -v $name验证
-    fn exists(&self) -> bool {
-        Command::new("sh")
-            .arg("-c")
-            .arg("command")
-            .arg("-v")
-            .arg(self.get_name())
-            .spawn()
-            .and_then(|mut child| child.wait())
-            .map(|status| status.success())
-            .unwrap_or(false)
-    }
-}
 #[allow(unused)]
 pub struct ZshProgram<'a> {
     zshrc_content: &'a ShellConfiguration,
@@ -231,6 +558,10 @@ impl ShellConfiguration {
         }
     }
 
+    pub fn get_content(&self) -> &str {
+        &self.content
+    }
+
     /// export已存在的变量并返回该变量，如果不存在则返回None
     pub fn export_var(&mut self, name: &str) -> Option<String> {
         let reg = Regex::new(&(r"(?m)^(\s*)(\w*?)(\s*)".to_string() + name + r"=(.+?)$")).unwrap();
@@ -469,6 +800,214 @@ source $ZSH/oh-my-zsh.sh
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// 在dir中创建只记录参数的fake pacman与sudo，`installed`文件中为已安装的包
+    fn fake_pacman(dir: &Path) {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let pacman = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "$@" >> "$dir/log"
+case "$1" in
+    -Q) grep -qx "$2" "$dir/installed" && echo "$2 1.0-1" ;;
+    -S) shift 3; for name in "$@"; do echo "$name" >> "$dir/installed"; done ;;
+    -Qdtq) [ -s "$dir/orphans" ] && cat "$dir/orphans" ;;
+    -Rn|-Rs)
+        shift 2
+        printf '%s\n' "$@" > "$dir/remove"
+        grep -vxF -f "$dir/remove" "$dir/installed" > "$dir/rest"
+        mv "$dir/rest" "$dir/installed" ;;
+esac
+"#;
+        // 以sudo的PATH查找命令，这里为dir
+        let sudo = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "sudo $@" >> "$dir/log"
+[ "$1" = "-n" ] && shift
+name=$1
+shift
+exec "$dir/$name" "$@"
+"#;
+        for (name, script) in [("pacman", pacman), ("sudo", sudo)] {
+            let path = dir.join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(dir.join("installed"), "").unwrap();
+    }
+
+    #[test]
+    fn pacman_install_and_uninstall() {
+        let dir = env::temp_dir().join("auto_configuration_fake_pacman");
+        fake_pacman(&dir);
+        let pm = PackageManager::Pacman;
+        let root = CommandLookup {
+            bin_dir: Some(&dir),
+            root: true,
+        };
+        let user = CommandLookup {
+            bin_dir: Some(&dir),
+            root: false,
+        };
+        let installed = |name| pm.installed_version_with(root, name).unwrap();
+        assert_eq!(installed("zsh"), None);
+
+        let refuse = PrivilegeStrategy::Refuse;
+        assert_eq!(
+            pm.install_with(user, vec!["zsh"], refuse)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        pm.install_with(root, vec!["zsh"], refuse).unwrap();
+        // 不是root时通过sudo -n执行
+        pm.install_with(
+            user,
+            vec!["git", "curl"],
+            PrivilegeStrategy::SudoNonInteractive,
+        )
+        .unwrap();
+        assert_eq!(installed("zsh"), Some("1.0-1".to_string()));
+        assert_eq!(installed("curl"), Some("1.0-1".to_string()));
+
+        let options = UninstallOptions {
+            purge: true,
+            remove_orphans: true,
+        };
+        // curl只被zsh依赖
+        fs::write(dir.join("orphans"), "curl\n").unwrap();
+        pm.uninstall_with(user, vec!["zsh"], options, PrivilegeStrategy::Sudo)
+            .unwrap();
+        assert_eq!(installed("zsh"), None);
+        assert_eq!(installed("curl"), None);
+        assert!(installed("git").is_some());
+        assert_eq!(
+            fs::read_to_string(dir.join("log")).unwrap(),
+            "-Q zsh
+-S --needed --noconfirm zsh
+sudo -n pacman -S --needed --noconfirm git curl
+-S --needed --noconfirm git curl
+-Q zsh
+-Q curl
+sudo pacman -Rn --noconfirm zsh
+-Rn --noconfirm zsh
+-Qdtq
+sudo pacman -Rs --noconfirm curl
+-Rs --noconfirm curl
+-Q zsh
+-Q curl
+-Q git
+"
+        );
+        assert_eq!(
+            PackageManager::Other
+                .install_with(root, vec!["zsh"], refuse)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::Unsupported
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn package_manager_commands() {
+        let args = |pm: PackageManager, prefix: &[&str]| {
+            let command = pm.build_command(CommandLookup::system(), prefix, &pm.get_install_args());
+            let mut args = vec![command.get_program().to_string_lossy().to_string()];
+            args.extend(
+                command
+                    .get_args()
+                    .map(|arg| arg.to_string_lossy().to_string()),
+            );
+            args.join(" ")
+        };
+        assert_eq!(args(PackageManager::Dnf, &[]), "dnf install -y");
+        assert_eq!(
+            args(PackageManager::AptGet, &["sudo", "-n"]),
+            "sudo -n env DEBIAN_FRONTEND=noninteractive apt-get install -y"
+        );
+        assert_eq!(
+            args(PackageManager::Zypper, &["pkexec"]),
+            "pkexec zypper --non-interactive install"
+        );
+        assert_eq!(args(PackageManager::Npm, &[]), "npm install -g");
+        assert_eq!(
+            describe_command(&PackageManager::AptGet.build_command(
+                CommandLookup::system(),
+                &[],
+                &["install", "-y"]
+            )),
+            "DEBIAN_FRONTEND=noninteractive apt-get install -y"
+        );
+        let purge = UninstallOptions {
+            purge: true,
+            remove_orphans: false,
+        };
+        assert_eq!(
+            PackageManager::AptGet.get_uninstall_args(purge),
+            vec!["purge", "-y"]
+        );
+        assert_eq!(
+            PackageManager::Pacman.get_uninstall_args(purge),
+            vec!["-Rn", "--noconfirm"]
+        );
+        let purge_orphans = UninstallOptions {
+            purge: true,
+            remove_orphans: true,
+        };
+        assert_eq!(
+            PackageManager::AptGet.get_uninstall_args(purge_orphans),
+            vec!["purge", "-y"]
+        );
+        assert_eq!(
+            PackageManager::Pacman.get_uninstall_args(purge_orphans),
+            vec!["-Rn", "--noconfirm"]
+        );
+        assert_eq!(
+            PackageManager::Apk.get_uninstall_args(purge_orphans),
+            vec!["del"]
+        );
+        assert_eq!(
+            PackageManager::Zypper.get_uninstall_args(UninstallOptions {
+                purge: false,
+                remove_orphans: true,
+            }),
+            vec!["--non-interactive", "remove", "--clean-deps"]
+        );
+        assert!(PackageManager::Apk.is_system());
+        assert_eq!(
+            PrivilegeStrategy::Auto.get_prefix(true, |_| false).unwrap(),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            PrivilegeStrategy::Auto
+                .get_prefix(false, |command| command == "pkexec")
+                .unwrap(),
+            vec!["pkexec"]
+        );
+        assert_eq!(
+            PrivilegeStrategy::Auto.get_prefix(false, |_| true).unwrap(),
+            vec!["sudo", "-n"]
+        );
+        for privilege in [PrivilegeStrategy::Auto, PrivilegeStrategy::Sudo] {
+            assert_eq!(
+                privilege.get_prefix(false, |_| false).unwrap_err().kind(),
+                io::ErrorKind::PermissionDenied
+            );
+        }
+        assert!(PrivilegeStrategy::Refuse
+            .get_prefix(false, |_| true)
+            .is_err());
+        assert_eq!(PrivilegeStrategy::Auto.describe_prefix(false), vec!["sudo", "-n"]);
+        assert!(PrivilegeStrategy::Refuse.describe_prefix(false).is_empty());
+        assert!(PrivilegeStrategy::Pkexec.describe_prefix(true).is_empty());
+        assert!(!PackageManager::Pipx.is_system());
+        let pm: PackageManager =
+            toml::from_str::<HashMap<String, PackageManager>>("package-manager = \"apt-get\"")
+                .unwrap()["package-manager"];
+        assert_eq!(pm, PackageManager::AptGet);
+    }
 
     // #[test]
     // fn basics() {
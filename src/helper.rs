@@ -0,0 +1,712 @@
+use crate::mapping;
+use crate::reconcile::list_source_files;
+use crate::{BackupContext, Configuration};
+
+use notify::RecursiveMode;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// helper读写的文件权限只能在该范围内，不允许setuid/setgid/sticky
+const MODE_MASK: u32 = 0o777;
+
+/// 请求中path的长度上限
+const MAX_PATH_LEN: usize = libc::PATH_MAX as usize;
+
+/// 读写的文件内容与list结果的长度上限，避免按对方给出的长度分配任意大的内存
+const MAX_CONTENT_LEN: usize = 64 << 20;
+
+/// 以root运行的最小helper，只负责读写from_paths下的文件
+///
+/// BackupServer以普通用户运行，读写没有权限的文件时通过helper的stdin/stdout请求：
+///
+/// ```text
+/// read <path_len>\n<path>
+/// write <path_len> <len> <mode|->\n<path><content>
+/// list <path_len> <recursive 0|1>\n<path>
+/// ```
+///
+/// 响应为`ok <mode> <len>\n<content>`或`err <message>\n`，list的content为
+/// 多个`<secs> <nanos> <len> <path_len>\n<path>`，供轮询没有权限的目录。
+/// path_len不超过[MAX_PATH_LEN]，len不超过[MAX_CONTENT_LEN]
+pub struct PrivilegedHelper {
+    /// 如`sudo -n auto-configuration helper`
+    command: Vec<String>,
+    /// 第一次请求时启动
+    process: Mutex<Option<HelperProcess>>,
+}
+
+struct HelperProcess {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Drop for HelperProcess {
+    fn drop(&mut self) {
+        // 关闭stdin后helper会退出
+        self.stdin.take();
+        let _ = self.child.wait();
+    }
+}
+
+impl PrivilegedHelper {
+    pub fn new(command: Vec<String>) -> io::Result<Self> {
+        if command.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "helper command不能为空",
+            ));
+        }
+        Ok(PrivilegedHelper {
+            command,
+            process: Mutex::new(None),
+        })
+    }
+
+    /// 读取path的内容与权限
+    pub fn read(&self, path: &Path) -> io::Result<(Vec<u8>, u32)> {
+        self.request(|stdin, stdout| {
+            write_read_request(stdin, path)?;
+            read_response(stdout)
+        })
+    }
+
+    /// 写入path，mode为None时新文件使用默认权限，已存在的文件权限不变
+    pub fn write(&self, path: &Path, content: &[u8], mode: Option<u32>) -> io::Result<()> {
+        self.request(|stdin, stdout| {
+            write_write_request(stdin, path, content, mode)?;
+            read_response(stdout).map(|_| ())
+        })
+    }
+
+    /// 列出path下的普通文件与其mtime、大小
+    pub fn list(
+        &self,
+        path: &Path,
+        mode: RecursiveMode,
+    ) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        self.request(|stdin, stdout| {
+            write_list_request(stdin, path, mode)?;
+            let (content, _) = read_response(stdout)?;
+            parse_entries(&content)
+        })
+    }
+
+    fn request<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut ChildStdin, &mut BufReader<ChildStdout>) -> io::Result<T>,
+    {
+        let mut process = self.process.lock().unwrap();
+        if process.is_none() {
+            *process = Some(self.spawn()?);
+        }
+        let p = process.as_mut().unwrap();
+        let res = f(p.stdin.as_mut().unwrap(), &mut p.stdout);
+        // helper返回的错误不影响后续请求，其它错误时下次重新启动helper
+        if let Err(e) = &res {
+            if e.kind() != io::ErrorKind::Other {
+                *process = None;
+            }
+        }
+        res
+    }
+
+    fn spawn(&self) -> io::Result<HelperProcess> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        Ok(HelperProcess {
+            stdin: child.stdin.take(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        })
+    }
+}
+
+/// 超出上限的请求不会被helper处理，不发送
+fn check_request_len(path: &[u8], content_len: usize) -> io::Result<()> {
+    if path.len() > MAX_PATH_LEN || content_len > MAX_CONTENT_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "请求超出helper的上限: path长度{}，内容长度{}",
+                path.len(),
+                content_len
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn write_read_request<W: Write>(w: &mut W, path: &Path) -> io::Result<()> {
+    let path = path.as_os_str().as_bytes();
+    check_request_len(path, 0)?;
+    writeln!(w, "read {}", path.len())?;
+    w.write_all(path)?;
+    w.flush()
+}
+
+fn write_write_request<W: Write>(
+    w: &mut W,
+    path: &Path,
+    content: &[u8],
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let path = path.as_os_str().as_bytes();
+    check_request_len(path, content.len())?;
+    let mode = mode
+        .map(|mode| mode.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    writeln!(w, "write {} {} {}", path.len(), content.len(), mode)?;
+    w.write_all(path)?;
+    w.write_all(content)?;
+    w.flush()
+}
+
+fn write_list_request<W: Write>(w: &mut W, path: &Path, mode: RecursiveMode) -> io::Result<()> {
+    let path = path.as_os_str().as_bytes();
+    check_request_len(path, 0)?;
+    let recursive = if mode == RecursiveMode::Recursive {
+        1
+    } else {
+        0
+    };
+    writeln!(w, "list {} {}", path.len(), recursive)?;
+    w.write_all(path)?;
+    w.flush()
+}
+
+fn parse_entries(content: &[u8]) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let mut r = content;
+    let mut entries = vec![];
+    while !r.is_empty() {
+        let mut line = String::new();
+        r.read_line(&mut line)?;
+        let fields = line.trim_end().split(' ').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [secs, nanos, len, path_len] => {
+                let modified = UNIX_EPOCH
+                    + Duration::new(
+                        secs.parse().map_err(invalid_data)?,
+                        nanos.parse().map_err(invalid_data)?,
+                    );
+                let len = len.parse().map_err(invalid_data)?;
+                entries.push((read_path(&mut r, path_len)?, modified, len));
+            }
+            _ => return Err(invalid_data(format!("无法解析helper响应: {}", line))),
+        }
+    }
+    Ok(entries)
+}
+
+fn read_response<R: BufRead>(r: &mut R) -> io::Result<(Vec<u8>, u32)> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "helper已退出"));
+    }
+    let line = line.trim_end();
+    if let Some(message) = line.strip_prefix("err ") {
+        return Err(io::Error::other(format!("helper: {}", message)));
+    }
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["ok", mode, len] => {
+            let mode = mode.parse().map_err(invalid_data)?;
+            let content = read_exact(r, len, MAX_CONTENT_LEN)?;
+            Ok((content, mode))
+        }
+        _ => Err(invalid_data(format!("无法解析helper响应: {}", line))),
+    }
+}
+
+/// 读取len个字节，len超过max时返回ErrorKind::InvalidData，之后的数据无法继续解析
+fn read_exact<R: Read>(r: &mut R, len: &str, max: usize) -> io::Result<Vec<u8>> {
+    let len = len.parse::<usize>().map_err(invalid_data)?;
+    if len > max {
+        return Err(invalid_data(format!("长度: {} 超出上限{}", len, max)));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// helper的请求循环，r结束时返回。只允许读写configurations的from_paths下的普通文件，
+/// 从根目录开始逐级以O_NOFOLLOW打开，检查的路径与实际读写的文件一致
+pub fn serve<R: BufRead, W: Write>(
+    configurations: &[Configuration],
+    r: &mut R,
+    w: &mut W,
+) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let fields = line.trim_end().split(' ').collect::<Vec<_>>();
+        let res = match fields.as_slice() {
+            ["read", path_len] => {
+                let path = read_path(r, path_len)?;
+                check_allowed(configurations, &path).and_then(|path| read_nofollow(&path))
+            }
+            ["write", path_len, len, mode] => {
+                let path = read_path(r, path_len)?;
+                let content = read_exact(r, len, MAX_CONTENT_LEN)?;
+                parse_mode(mode)
+                    .and_then(|mode| {
+                        let path = check_allowed(configurations, &path)?;
+                        write_nofollow(&path, &content, mode)
+                    })
+                    .map(|_| (vec![], 0))
+            }
+            ["list", path_len, recursive] => {
+                let path = read_path(r, path_len)?;
+                let mode = match *recursive {
+                    "1" => RecursiveMode::Recursive,
+                    _ => RecursiveMode::NonRecursive,
+                };
+                check_allowed(configurations, &path)
+                    .and_then(|path| list_nofollow(&path, mode))
+                    .map(|content| (content, 0))
+            }
+            // 无法继续解析后续请求
+            _ => return Err(invalid_data(format!("无法解析请求: {}", line.trim_end()))),
+        };
+        let res = res.and_then(|(content, mode)| {
+            if content.len() > MAX_CONTENT_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("响应长度: {} 超出上限{}", content.len(), MAX_CONTENT_LEN),
+                ));
+            }
+            Ok((content, mode))
+        });
+        match res {
+            Ok((content, mode)) => {
+                writeln!(w, "ok {} {}", mode, content.len())?;
+                w.write_all(&content)?;
+            }
+            Err(e) => writeln!(w, "err {}", e.to_string().replace('\n', " "))?,
+        }
+        w.flush()?;
+    }
+}
+
+fn read_path<R: Read>(r: &mut R, path_len: &str) -> io::Result<PathBuf> {
+    let path = read_exact(r, path_len, MAX_PATH_LEN)?;
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(&path)))
+}
+
+/// mode必须在[MODE_MASK]内，`-`表示不修改
+fn parse_mode(mode: &str) -> io::Result<Option<u32>> {
+    if mode == "-" {
+        return Ok(None);
+    }
+    let mode = mode.parse::<u32>().map_err(invalid_data)?;
+    if mode & !MODE_MASK != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("mode: {:o} 超出{:o}", mode, MODE_MASK),
+        ));
+    }
+    Ok(Some(mode))
+}
+
+/// 返回规范化后的path，path必须为绝对路径且在from_paths下
+///
+/// 规范化会解析路径中的符号链接，之后只能以[open_parent]打开返回的路径，
+/// 检查之后被替换为符号链接的路径会打开失败
+fn check_allowed(configurations: &[Configuration], path: &Path) -> io::Result<PathBuf> {
+    let denied = || {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("path: {} 不在from_paths中", path.display()),
+        )
+    };
+    if !path.is_absolute() {
+        return Err(denied());
+    }
+    let path = mapping::normalize(path)?;
+    if !configurations.iter().any(|config| config.contains(&path)) {
+        return Err(denied());
+    }
+    Ok(path)
+}
+
+fn not_regular_file(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("path: {} 不是普通文件", path.display()),
+    )
+}
+
+/// 从根目录开始逐级以O_NOFOLLOW打开path的父目录，返回父目录与文件名，
+/// create时创建不存在的目录。任何一级是符号链接时失败
+fn open_parent(path: &Path, create: bool) -> io::Result<(File, &OsStr)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("path: {} 不是规范化的绝对路径", path.display()),
+        )
+    };
+    let name = path.file_name().ok_or_else(invalid)?;
+    let mut dir = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open("/")?;
+    for component in path.parent().ok_or_else(invalid)?.components() {
+        let name = match component {
+            Component::RootDir => continue,
+            Component::Normal(name) => name,
+            _ => return Err(invalid()),
+        };
+        dir = match open_at(&dir, name, libc::O_DIRECTORY | libc::O_NOFOLLOW, 0) {
+            Err(e) if create && e.kind() == io::ErrorKind::NotFound => {
+                mkdir_at(&dir, name)?;
+                open_at(&dir, name, libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?
+            }
+            res => res?,
+        };
+    }
+    Ok((dir, name))
+}
+
+fn to_cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(invalid_data)
+}
+
+/// openat(2)，总是带有O_CLOEXEC
+fn open_at(dir: &File, name: &OsStr, flags: libc::c_int, mode: u32) -> io::Result<File> {
+    let name = to_cstring(name)?;
+    // name以NUL结尾，dir在调用期间有效
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // fd由openat新建，只由返回的File持有
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// mkdirat(2)，目录已存在时不报错
+fn mkdir_at(dir: &File, name: &OsStr) -> io::Result<()> {
+    let name = to_cstring(name)?;
+    // name以NUL结尾，dir在调用期间有效
+    if unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// 读取普通文件path的内容与权限
+fn read_nofollow(path: &Path) -> io::Result<(Vec<u8>, u32)> {
+    let (dir, name) = open_parent(path, false)?;
+    // O_NONBLOCK避免打开fifo时阻塞，对普通文件没有影响
+    let file = open_at(
+        &dir,
+        name,
+        libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK,
+        0,
+    )?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(not_regular_file(path));
+    }
+    // 超出上限时只读取MAX_CONTENT_LEN + 1个字节，由serve返回错误
+    let mut content = vec![];
+    file.take(MAX_CONTENT_LEN as u64 + 1)
+        .read_to_end(&mut content)?;
+    Ok((content, metadata.permissions().mode() & MODE_MASK))
+}
+
+/// 写入普通文件path，mode为None时新文件为0644，已存在的文件权限不变
+fn write_nofollow(path: &Path, content: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let (dir, name) = open_parent(path, true)?;
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW | libc::O_NONBLOCK;
+    let mut file = open_at(&dir, name, flags, mode.unwrap_or(0o644))?;
+    if !file.metadata()?.is_file() {
+        return Err(not_regular_file(path));
+    }
+    file.set_len(0)?;
+    file.write_all(content)?;
+    if let Some(mode) = mode {
+        // 创建时的mode受umask影响
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// 列出目录path下的普通文件，不跟随符号链接，返回[parse_entries]的格式
+fn list_nofollow(path: &Path, mode: RecursiveMode) -> io::Result<Vec<u8>> {
+    let (parent, name) = open_parent(path, false)?;
+    let dir = open_at(&parent, name, libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+    let mut out = vec![];
+    list_dir(&dir, path, mode, &mut out)?;
+    Ok(out)
+}
+
+fn list_dir(dir: &File, path: &Path, mode: RecursiveMode, out: &mut Vec<u8>) -> io::Result<()> {
+    // 通过fd读取目录，目录在打开后被替换时仍然读取原来的目录
+    let fd_path = PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()));
+    for entry in fs::read_dir(&fd_path)? {
+        let name = entry?.file_name();
+        let metadata = match fs::symlink_metadata(fd_path.join(&name)) {
+            Ok(metadata) => metadata,
+            // 读取期间被删除
+            Err(_) => continue,
+        };
+        if metadata.is_file() {
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let file_path = path.join(&name);
+            let file_path = file_path.as_os_str().as_bytes();
+            writeln!(
+                out,
+                "{} {} {} {}",
+                modified.as_secs(),
+                modified.subsec_nanos(),
+                metadata.len(),
+                file_path.len()
+            )?;
+            out.extend_from_slice(file_path);
+        } else if metadata.is_dir() && mode == RecursiveMode::Recursive {
+            if let Ok(sub) = open_at(dir, &name, libc::O_DIRECTORY | libc::O_NOFOLLOW, 0) {
+                list_dir(&sub, &path.join(&name), mode, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, content: &[u8], mode: Option<u32>) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+impl BackupContext {
+    pub fn set_helper(&mut self, helper: PrivilegedHelper) {
+        self.helper = Some(Arc::new(helper));
+    }
+
+    /// 轮询没有权限的目录时使用
+    pub fn get_helper(&self) -> Option<Arc<PrivilegedHelper>> {
+        self.helper.clone()
+    }
+
+    /// 读取源文件path，没有读权限时通过helper读取
+    pub(crate) fn read_source(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match (fs::read(path), &self.helper) {
+            (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => {
+                helper.read(path).map(|(content, _)| content)
+            }
+            (res, _) => res,
+        }
+    }
+
+    /// 列出path下的源文件，见[crate::reconcile::list_source_files]，没有读权限时通过helper列出
+    pub(crate) fn list_sources(
+        &self,
+        path: &Path,
+        mode: RecursiveMode,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        match (list_source_files(path, mode, &mut files), &self.helper) {
+            (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => Ok(helper
+                .list(path, mode)?
+                .into_iter()
+                .map(|(file, _, _)| file)
+                .collect()),
+            (res, _) => res.map(|_| files),
+        }
+    }
+
+    /// 复制源文件from_path到backup_path，没有读权限时通过helper读取
+    pub(crate) fn copy_from_source(&self, from_path: &Path, backup_path: &Path) -> io::Result<()> {
+        match (fs::copy(from_path, backup_path), &self.helper) {
+            (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => {
+                let (content, mode) = helper.read(from_path)?;
+                fs::write(backup_path, content)?;
+                fs::set_permissions(backup_path, fs::Permissions::from_mode(mode & MODE_MASK))
+            }
+            (res, _) => res.map(|_| ()),
+        }
+    }
+
+    /// 复制backup_path到源文件to_path，没有写权限时通过helper写入
+    pub(crate) fn copy_to_source(&self, backup_path: &Path, to_path: &Path) -> io::Result<()> {
+        let res = to_path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::copy(backup_path, to_path));
+        match (res, &self.helper) {
+            (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => {
+                let mode = fs::metadata(backup_path)?.permissions().mode() & MODE_MASK;
+                helper.write(to_path, &fs::read(backup_path)?, Some(mode))
+            }
+            (res, _) => res.map(|_| ()),
+        }
+    }
+
+    /// 写入源文件path，mode为None时不修改权限，没有写权限时通过helper写入
+    pub(crate) fn write_to_source(
+        &self,
+        path: &Path,
+        content: &[u8],
+        mode: Option<u32>,
+    ) -> io::Result<()> {
+        match (write_file(path, content, mode), &self.helper) {
+            (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => {
+                helper.write(path, content, mode)
+            }
+            (res, _) => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::RecursiveMode;
+    use std::env;
+    use std::io::Cursor;
+    use std::os::unix::fs::symlink;
+
+    fn serve_requests(configurations: &[Configuration], requests: Vec<u8>) -> Vec<u8> {
+        let mut out = vec![];
+        serve(configurations, &mut Cursor::new(requests), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn read_and_write_allowed_paths() {
+        let dir = env::temp_dir().join("auto_configuration_helper");
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.cnf"), "a").unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        symlink(dir.join("secret"), src.join("link")).unwrap();
+        let configurations = vec![Configuration::for_test("test", &[&src])];
+
+        let mut requests = vec![];
+        write_read_request(&mut requests, &src.join("a.cnf")).unwrap();
+        write_write_request(&mut requests, &src.join("d/b.cnf"), b"b", Some(0o600)).unwrap();
+        write_read_request(&mut requests, &src.join("../secret")).unwrap();
+        write_read_request(&mut requests, &src.join("link")).unwrap();
+        write_write_request(&mut requests, Path::new("d/b.cnf"), b"b", None).unwrap();
+        write_write_request(&mut requests, &src.join("a.cnf"), b"s", Some(0o4755)).unwrap();
+        write_list_request(&mut requests, &src, RecursiveMode::Recursive).unwrap();
+        let mut out = Cursor::new(serve_requests(&configurations, requests));
+
+        let (content, _) = read_response(&mut out).unwrap();
+        assert_eq!(content, b"a");
+        assert_eq!(read_response(&mut out).unwrap(), (vec![], 0));
+        assert_eq!(fs::read(src.join("d/b.cnf")).unwrap(), b"b");
+        assert_eq!(
+            fs::metadata(src.join("d/b.cnf"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            0o600
+        );
+        for _ in 0..4 {
+            assert!(read_response(&mut out).is_err());
+        }
+        assert_eq!(fs::read(src.join("a.cnf")).unwrap(), b"a");
+        // 不包含符号链接
+        let (content, _) = read_response(&mut out).unwrap();
+        let mut files = parse_entries(&content)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _, len)| (path, len))
+            .collect::<Vec<_>>();
+        files.sort();
+        let src = mapping::normalize(&src).unwrap();
+        assert_eq!(
+            files,
+            vec![(src.join("a.cnf"), 1), (src.join("d/b.cnf"), 1)]
+        );
+        assert_eq!(
+            read_response(&mut out).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nofollow_parent() {
+        let dir = env::temp_dir().join("auto_configuration_helper_nofollow");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        symlink(dir.join("real"), dir.join("link")).unwrap();
+        let dir = mapping::normalize(&dir).unwrap();
+
+        // 检查之后父目录被替换为符号链接
+        let res = write_nofollow(&dir.join("link/a.cnf"), b"a", None);
+        assert!(res.is_err());
+        assert!(!dir.join("real/a.cnf").exists());
+        assert!(read_nofollow(&dir.join("link/a.cnf")).is_err());
+
+        write_nofollow(&dir.join("real/b/a.cnf"), b"a", Some(0o640)).unwrap();
+        assert_eq!(
+            read_nofollow(&dir.join("real/b/a.cnf")).unwrap(),
+            (b"a".to_vec(), 0o640)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_request() {
+        let mut out = vec![];
+        let res = serve(&[], &mut Cursor::new(b"delete /etc\n".to_vec()), &mut out);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+
+        // 超出上限的长度不分配内存，直接结束
+        for request in [
+            format!("read {}\n/etc", usize::MAX),
+            format!("write 4 {} -\n/etc", MAX_CONTENT_LEN + 1),
+        ] {
+            let res = serve(&[], &mut Cursor::new(request.into_bytes()), &mut out);
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(out.is_empty());
+        let path = PathBuf::from(format!("/{}", "a".repeat(MAX_PATH_LEN)));
+        assert_eq!(
+            write_read_request(&mut out, &path).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::temp::TempDir;
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// 备份仓库中的一次提交
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub id: String,
+    /// unix时间戳，单位：秒
+    pub time: u64,
+    /// 如：`2020-08-01 18:00:00 +0800`
+    pub date: String,
+    pub message: String,
+}
+
+/// 在repo_path下执行git并返回stdout，退出码不为0时返回包含stderr的错误
+pub fn git<I, S>(repo_path: &Path, args: I) -> io::Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let out = Command::new("git")
+        .args(args)
+        .current_dir(repo_path)
+        .output()?;
+    check_output(out).map(|out| out.stdout)
+}
+
+fn check_output(out: Output) -> io::Result<Output> {
+    if out.status.success() {
+        Ok(out)
+    } else {
+        Err(io::Error::other(format!(
+            "git error: code={:?}, {}",
+            out.status.code(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )))
+    }
+}
+
+/// 获取修改过path的所有提交，最新的在前
+///
+/// path为相对于repo_path的备份文件路径
+pub fn log(repo_path: &Path, path: &Path) -> io::Result<Vec<Commit>> {
+    let out = git(
+        repo_path,
+        vec![
+            OsStr::new("log"),
+            OsStr::new("--format=%H%x1f%at%x1f%ai%x1f%s"),
+            OsStr::new("--"),
+            path.as_os_str(),
+        ],
+    )?;
+    String::from_utf8_lossy(&out)
+        .lines()
+        .map(parse_commit)
+        .collect()
+}
+
+fn parse_commit(line: &str) -> io::Result<Commit> {
+    let fields = line.split('\u{1f}').collect::<Vec<_>>();
+    match fields.as_slice() {
+        [id, time, date, message] => Ok(Commit {
+            id: id.to_string(),
+            time: time
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            date: date.to_string(),
+            message: message.to_string(),
+        }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("无法解析git log: {}", line),
+        )),
+    }
+}
+
+/// 获取path在revision时的内容
+pub fn show(repo_path: &Path, revision: &str, path: &Path) -> io::Result<Vec<u8>> {
+    let mut object = revision.as_bytes().to_vec();
+    object.push(b':');
+    object.extend_from_slice(path_bytes(path));
+    git(
+        repo_path,
+        vec![OsStr::new("show"), bytes_to_os_str(&object)],
+    )
+}
+
+/// 获取time之前(含)的最后一个提交，time支持git的日期格式如`yesterday 18:00`、`2020-08-01 18:00`
+pub fn revision_before(repo_path: &Path, time: &str) -> io::Result<String> {
+    let out = git(
+        repo_path,
+        ["rev-list", "-1", &format!("--before={}", time), "HEAD"],
+    )?;
+    let revision = String::from_utf8_lossy(&out).trim().to_string();
+    if revision.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} 之前没有提交", time),
+        ));
+    }
+    Ok(revision)
+}
+
+/// 获取revision时dirs下的所有文件，返回相对于repo_path的路径
+pub fn list_files(repo_path: &Path, revision: &str, dirs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut args = vec![
+        OsStr::new("ls-tree"),
+        OsStr::new("-r"),
+        OsStr::new("-z"),
+        OsStr::new("--name-only"),
+        OsStr::new(revision),
+        OsStr::new("--"),
+    ];
+    args.extend(dirs.iter().map(|dir| dir.as_os_str()));
+    let out = git(repo_path, args)?;
+    Ok(out
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(bytes_to_os_str(name)))
+        .collect())
+}
+
+/// 提交repo_path下的所有修改，没有修改时不提交并返回false
+pub fn commit_all(repo_path: &Path, message: &str) -> io::Result<bool> {
+    git(repo_path, ["add", "-A"])?;
+    if git(repo_path, ["status", "--porcelain"])?.is_empty() {
+        return Ok(false);
+    }
+    git(repo_path, ["commit", "-q", "-m", message])?;
+    Ok(true)
+}
+
+/// 比较path在两个revision之间的差异
+pub fn diff_revisions(repo_path: &Path, from: &str, to: &str, path: &Path) -> io::Result<String> {
+    let out = git(
+        repo_path,
+        vec![
+            OsStr::new("diff"),
+            OsStr::new(from),
+            OsStr::new(to),
+            OsStr::new("--"),
+            path.as_os_str(),
+        ],
+    )?;
+    Ok(String::from_utf8_lossy(&out).to_string())
+}
+
+/// 比较live_path的当前内容与path在revision时的内容
+pub fn diff_live(
+    repo_path: &Path,
+    revision: &str,
+    path: &Path,
+    live_path: &Path,
+) -> io::Result<String> {
+    let old = show(repo_path, revision, path)?;
+    let tmp_dir = TempDir::new("diff")?;
+    let tmp_path = tmp_dir.write_file(revision, &old)?;
+    let out = Command::new("git")
+        .arg("diff")
+        .arg("--no-index")
+        .arg("--")
+        .arg(&tmp_path)
+        .arg(live_path)
+        .output()?;
+    // --no-index存在差异时退出码为1
+    if out.status.code() == Some(1) {
+        return Ok(String::from_utf8_lossy(&out.stdout).to_string());
+    }
+    check_output(out).map(|out| String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+fn path_bytes(path: &Path) -> &[u8] {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes()
+}
+
+fn bytes_to_os_str(bytes: &[u8]) -> &OsStr {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// 在dir初始化一个可提交的git仓库
+    pub fn init_repo(dir: &Path) {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        git(dir, ["init", "-q"]).unwrap();
+        git(dir, ["config", "user.name", "test"]).unwrap();
+        git(dir, ["config", "user.email", "test@example.com"]).unwrap();
+    }
+
+    pub fn commit_file(dir: &Path, path: &str, content: &str, message: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        assert!(commit_all(dir, message).unwrap());
+    }
+
+    #[test]
+    fn log_show_and_diff() {
+        let dir = env::temp_dir().join("auto_configuration_history");
+        init_repo(&dir);
+        commit_file(&dir, "etc/a.txt", "1\n", "first");
+        commit_file(&dir, "etc/b.txt", "b\n", "other");
+        commit_file(&dir, "etc/a.txt", "2\n", "second");
+
+        let path = PathBuf::from("etc/a.txt");
+        let commits = log(&dir, &path).unwrap();
+        assert_eq!(
+            commits
+                .iter()
+                .map(|c| c.message.as_str())
+                .collect::<Vec<_>>(),
+            vec!["second", "first"]
+        );
+        assert_eq!(show(&dir, &commits[1].id, &path).unwrap(), b"1\n");
+        assert_eq!(show(&dir, "HEAD", &path).unwrap(), b"2\n");
+
+        let diff = diff_revisions(&dir, &commits[1].id, &commits[0].id, &path).unwrap();
+        assert!(diff.contains("-1\n+2\n"));
+
+        let live = dir.join("live.txt");
+        fs::write(&live, "3\n").unwrap();
+        assert!(diff_live(&dir, "HEAD", &path, &live)
+            .unwrap()
+            .contains("-2\n+3\n"));
+        fs::write(&live, "2\n").unwrap();
+        assert!(diff_live(&dir, "HEAD", &path, &live).unwrap().is_empty());
+
+        assert!(show(&dir, "HEAD", Path::new("none.txt")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revisions_and_files() {
+        let dir = env::temp_dir().join("auto_configuration_history_revisions");
+        init_repo(&dir);
+        commit_file(&dir, "etc/a.txt", "1\n", "first");
+        commit_file(&dir, "home/b.txt", "b\n", "second");
+        assert!(!commit_all(&dir, "nothing").unwrap());
+
+        assert_eq!(
+            revision_before(&dir, "now").unwrap(),
+            String::from_utf8(git(&dir, ["rev-parse", "HEAD"]).unwrap())
+                .unwrap()
+                .trim()
+        );
+        assert_eq!(
+            revision_before(&dir, "2000-01-01").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            list_files(&dir, "HEAD", &[PathBuf::from("etc")]).unwrap(),
+            vec![PathBuf::from("etc/a.txt")]
+        );
+        assert_eq!(list_files(&dir, "HEAD~1", &[]).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,412 @@
+use crate::temp::TempDir;
+use crate::BackupContext;
+
+use serde::Deserialize;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// hook执行时触发备份的源文件路径
+pub const HOOK_PATH_ENV: &str = "AUTO_CONFIGURATION_PATH";
+
+/// configuration的hooks
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Hooks {
+    /// 复制到备份目录前执行，用于校验配置，如`nginx -t`
+    #[serde(default)]
+    pub pre_commit: Vec<Hook>,
+    /// 提交后执行，如重新加载服务
+    #[serde(default)]
+    pub post_commit: Vec<Hook>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Hook {
+    /// 通过`sh -c`执行
+    pub command: String,
+    /// 单位：秒，超时后hook的整个进程组被kill并视为失败
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// 只对pre-commit有效
+    #[serde(default)]
+    pub on_failure: HookFailurePolicy,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+/// pre-commit hook失败时的处理
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookFailurePolicy {
+    /// 不复制文件到备份目录，因此不会提交
+    #[default]
+    SkipCommit,
+    /// 继续备份，失败记录在提交信息中
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStatus {
+    Success,
+    /// 退出码，被信号终止时为None
+    Failed(Option<i32>),
+    Timeout,
+}
+
+/// hook的执行结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookOutput {
+    pub command: String,
+    pub status: HookStatus,
+    /// stdout与stderr，超时时为kill前的输出
+    pub output: String,
+}
+
+impl HookOutput {
+    pub fn is_success(&self) -> bool {
+        self.status == HookStatus::Success
+    }
+
+    /// 如：`pre-commit "nginx -t": failed (code 1)`
+    pub fn summary(&self, stage: &str) -> String {
+        let status = match self.status {
+            HookStatus::Success => "ok".to_string(),
+            HookStatus::Failed(Some(code)) => format!("failed (code {})", code),
+            HookStatus::Failed(None) => "killed".to_string(),
+            HookStatus::Timeout => "timeout".to_string(),
+        };
+        format!("{} {:?}: {}", stage, self.command, status)
+    }
+}
+
+impl Hook {
+    /// 在新的进程组中执行hook，环境变量[HOOK_PATH_ENV]为path
+    pub fn run(&self, path: &Path) -> io::Result<HookOutput> {
+        let (status, output) = run_with_timeout(
+            Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .env(HOOK_PATH_ENV, path),
+            Duration::from_secs(self.timeout),
+        )?;
+        let status = match status.map(|status| status.code()) {
+            Some(Some(0)) => HookStatus::Success,
+            Some(code) => HookStatus::Failed(code),
+            None => HookStatus::Timeout,
+        };
+        Ok(HookOutput {
+            command: self.command.clone(),
+            status,
+            output: String::from_utf8_lossy(&output).to_string(),
+        })
+    }
+}
+
+/// 在新的进程组中执行command并等待timeout，超时时kill整个进程组并返回None作为退出状态
+///
+/// stdout与stderr写入同一个临时文件而不是pipe，command启动的后台进程继承输出时
+/// 不会阻塞读取，返回的输出为退出或超时时已写入的内容
+pub(crate) fn run_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> io::Result<(Option<ExitStatus>, Vec<u8>)> {
+    let dir = TempDir::new("output")?;
+    let path = dir.write_file("output", b"")?;
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let mut child = command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(file.try_clone()?)
+        .stderr(file.try_clone()?)
+        .spawn()?;
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            kill_process_group(&mut child)?;
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let mut output = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut output)?;
+    Ok((status, output))
+}
+
+/// kill child所在的进程组，包括hook启动的后台进程
+fn kill_process_group(child: &mut Child) -> io::Result<()> {
+    // 进程组id为child的pid，见process_group(0)
+    if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        child.kill()
+    }
+}
+
+impl BackupContext {
+    /// 执行path所属configuration的pre-commit hooks，结果会记录在下次提交path的提交信息中
+    ///
+    /// 策略为skip-commit的hook失败时返回ErrorKind::InvalidData，调用方不应再hold该文件，
+    /// 被跳过的修改同样记录在下次提交的信息中
+    pub fn run_pre_commit_hooks(&self, path: &Path) -> io::Result<()> {
+        let hooks = match self.find_configuration(path) {
+            Some(config) => &config.hooks.pre_commit,
+            None => return Ok(()),
+        };
+        let mut blocked = None;
+        let mut summaries = vec![];
+        for hook in hooks {
+            let output = hook.run(path)?;
+            let summary = output.summary("pre-commit");
+            if !output.is_success() {
+                eprintln!("{}\n{}", summary, output.output.trim_end());
+                if hook.on_failure == HookFailurePolicy::SkipCommit {
+                    summaries.push(format!("{}, commit skipped", summary));
+                    blocked = Some(summary);
+                    break;
+                }
+            }
+            summaries.push(summary);
+        }
+        if !summaries.is_empty() {
+            self.hook_outputs
+                .lock()
+                .unwrap()
+                .entry(path.to_path_buf())
+                .or_default()
+                .extend(summaries);
+        }
+        match blocked {
+            Some(summary) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("path: {} 跳过提交，{}", path.display(), summary),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// paths等待提交的pre-commit结果，作为提交信息的正文，提交后通过[Self::clear_hook_log]移除
+    pub(crate) fn get_hook_log(&self, paths: &[PathBuf]) -> String {
+        let outputs = self.hook_outputs.lock().unwrap();
+        let mut log = String::new();
+        for path in paths {
+            if let Some(summaries) = outputs.get(path) {
+                log.push_str(&format!("\n\n{}:", path.display()));
+                for summary in summaries {
+                    log.push_str(&format!("\n{}", summary));
+                }
+            }
+        }
+        log
+    }
+
+    pub(crate) fn clear_hook_log(&self, paths: &[PathBuf]) {
+        let mut outputs = self.hook_outputs.lock().unwrap();
+        for path in paths {
+            outputs.remove(path);
+        }
+    }
+
+    /// 执行path所属configuration的post-commit hooks，失败只会被输出
+    pub fn run_post_commit_hooks(&self, path: &Path) -> Vec<HookOutput> {
+        let hooks = match self.find_configuration(path) {
+            Some(config) => &config.hooks.post_commit,
+            None => return vec![],
+        };
+        let mut outputs = vec![];
+        for hook in hooks {
+            match hook.run(path) {
+                Ok(output) => {
+                    if !output.is_success() {
+                        eprintln!(
+                            "{}\n{}",
+                            output.summary("post-commit"),
+                            output.output.trim_end()
+                        );
+                    }
+                    outputs.push(output);
+                }
+                Err(e) => eprintln!("post-commit {:?} error: {}", hook.command, e),
+            }
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history;
+    use crate::Configuration;
+    use std::env;
+    use std::fs;
+
+    fn hook(command: &str, on_failure: HookFailurePolicy) -> Hook {
+        Hook {
+            command: command.to_string(),
+            timeout: 1,
+            on_failure,
+        }
+    }
+
+    #[test]
+    fn run_hook() {
+        let path = Path::new("/tmp/a.conf");
+        let output = hook(
+            "echo $AUTO_CONFIGURATION_PATH; echo err >&2",
+            HookFailurePolicy::SkipCommit,
+        )
+        .run(path)
+        .unwrap();
+        assert!(output.is_success());
+        assert_eq!(output.output, "/tmp/a.conf\nerr\n");
+
+        let output = hook("exit 3", HookFailurePolicy::SkipCommit)
+            .run(path)
+            .unwrap();
+        assert_eq!(output.status, HookStatus::Failed(Some(3)));
+        assert_eq!(
+            output.summary("pre-commit"),
+            "pre-commit \"exit 3\": failed (code 3)"
+        );
+
+        // 后台进程与sh在同一进程组中，超时时一起被kill
+        let marker = env::temp_dir().join("auto_configuration_hook_group");
+        let _ = fs::remove_file(&marker);
+        let command = format!("(sleep 1.5; touch {}) & sleep 5", marker.display());
+        let output = hook(&command, HookFailurePolicy::SkipCommit)
+            .run(path)
+            .unwrap();
+        assert_eq!(output.status, HookStatus::Timeout);
+        thread::sleep(Duration::from_secs(1));
+        assert!(!marker.exists());
+
+        // 后台进程继承了输出，hook退出后不等待它
+        let start = Instant::now();
+        let output = hook("sleep 3 & echo ok", HookFailurePolicy::SkipCommit)
+            .run(path)
+            .unwrap();
+        assert!(output.is_success());
+        assert_eq!(output.output, "ok\n");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn hooks_around_commit() {
+        let dir = env::temp_dir().join("auto_configuration_hooks");
+        let backup = dir.join("backup");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let marker = dir.join("reloaded");
+
+        let config = Configuration {
+            hooks: Hooks {
+                pre_commit: vec![
+                    hook("true", HookFailurePolicy::SkipCommit),
+                    hook(
+                        "grep -q valid $AUTO_CONFIGURATION_PATH",
+                        HookFailurePolicy::SkipCommit,
+                    ),
+                ],
+                post_commit: vec![hook(
+                    &format!("touch {}", marker.display()),
+                    HookFailurePolicy::Ignore,
+                )],
+            },
+            ..Configuration::for_test("test", &[&src])
+        };
+        let context = BackupContext::for_test(vec![config], &backup);
+
+        let a = src.join("a.conf");
+        fs::write(&a, "broken").unwrap();
+        let err = context.run_pre_commit_hooks(&a).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::write(&a, "valid").unwrap();
+        context.run_pre_commit_hooks(&a).unwrap();
+        context.hold(&a).unwrap();
+        context.commit(&a).unwrap();
+        assert!(marker.exists());
+
+        let body = history::git(&backup, ["log", "-1", "--format=%b"]).unwrap();
+        // 之前被跳过的修改也记录在提交信息中
+        assert_eq!(
+            String::from_utf8_lossy(&body).trim(),
+            format!(
+                "{0}
+
+{0}:
+pre-commit \"true\": ok
+pre-commit \"grep -q valid $AUTO_CONFIGURATION_PATH\": failed (code 1), commit skipped
+pre-commit \"true\": ok
+pre-commit \"grep -q valid $AUTO_CONFIGURATION_PATH\": ok",
+                a.display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn commit_other_held_paths() {
+        let dir = env::temp_dir().join("auto_configuration_hooks_held");
+        let _ = fs::remove_dir_all(&dir);
+        let backup = dir.join("backup");
+        let config = |name: &str| {
+            let src = dir.join(name);
+            fs::create_dir_all(&src).unwrap();
+            Configuration {
+                hooks: Hooks {
+                    pre_commit: vec![hook("true", HookFailurePolicy::SkipCommit)],
+                    post_commit: vec![hook(
+                        &format!("touch {}", dir.join(format!("{}.reloaded", name)).display()),
+                        HookFailurePolicy::Ignore,
+                    )],
+                },
+                ..Configuration::for_test(name, &[&src])
+            }
+        };
+        let context = BackupContext::for_test(vec![config("a"), config("b")], &backup);
+        let a = dir.join("a/a.conf");
+        let b = dir.join("b/b.conf");
+        for path in [&a, &b] {
+            fs::write(path, "1").unwrap();
+            context.run_pre_commit_hooks(path).unwrap();
+            context.hold(path).unwrap();
+        }
+
+        // 存储提交了所有已hold的文件，b的hook结果与post-commit也在a的提交中处理
+        context.commit(&a).unwrap();
+        assert!(dir.join("a.reloaded").exists());
+        assert!(dir.join("b.reloaded").exists());
+        let body = history::git(&backup, ["log", "-1", "--format=%b"]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&body).trim(),
+            format!(
+                "{0}\n{1}\n\n{0}:\npre-commit \"true\": ok\n\n{1}:\npre-commit \"true\": ok",
+                a.display(),
+                b.display()
+            )
+        );
+        assert!(context.hook_outputs.lock().unwrap().is_empty());
+        assert!(context.holding_paths.lock().unwrap().is_empty());
+
+        // b的计时到期时已经没有需要提交的修改
+        fs::remove_file(dir.join("b.reloaded")).unwrap();
+        context.commit(&b).unwrap();
+        assert!(!dir.join("b.reloaded").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// 各机器备份所在的目录：`$base/hosts/<id>`
+pub const HOSTS_DIR: &str = "hosts";
+
+/// 所有机器共享的备份目录：`$base/common`
+pub const COMMON_DIR: &str = "common";
+
+/// 当前机器的标识，用于将备份划分到`$base/hosts/<id>`下
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineProfile {
+    id: String,
+}
+
+impl MachineProfile {
+    /// id将作为目录名，不能为空或包含`/`
+    pub fn new(id: &str) -> io::Result<Self> {
+        let id = id.trim();
+        if id.is_empty() || id == "." || id == ".." || id.contains('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("非法的machine id: {}", id),
+            ));
+        }
+        Ok(MachineProfile { id: id.to_string() })
+    }
+
+    /// 使用当前机器的hostname作为id
+    pub fn from_hostname() -> io::Result<Self> {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| fs::read_to_string("/etc/hostname"))
+            .or_else(|_| {
+                let out = Command::new("hostname").output()?;
+                Ok::<_, io::Error>(String::from_utf8_lossy(&out.stdout).to_string())
+            })?;
+        MachineProfile::new(&hostname)
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_host_path(&self, base_path: &Path) -> PathBuf {
+        get_host_path(base_path, &self.id)
+    }
+}
+
+pub fn get_host_path(base_path: &Path, id: &str) -> PathBuf {
+    base_path.join(HOSTS_DIR).join(id)
+}
+
+pub fn get_common_path(base_path: &Path) -> PathBuf {
+    base_path.join(COMMON_DIR)
+}
+
+/// 两台机器备份之间的差异，路径为相对于各自host目录的备份路径
+#[derive(Debug, PartialEq)]
+pub enum MachineDiff {
+    /// 只在该id的机器上存在
+    OnlyIn(String, PathBuf),
+    /// 两台机器上都存在但内容不同
+    Changed(PathBuf),
+}
+
+/// 比较base_path下两台机器的备份文件
+pub fn diff(base_path: &Path, id: &str, other_id: &str) -> io::Result<Vec<MachineDiff>> {
+    let (path, other_path) = (
+        get_host_path(base_path, id),
+        get_host_path(base_path, other_id),
+    );
+    let files = list_relative_files(&path)?;
+    let other_files = list_relative_files(&other_path)?;
+
+    let mut diffs = vec![];
+    for file in &files {
+        if !other_files.contains(file) {
+            diffs.push(MachineDiff::OnlyIn(id.to_string(), file.clone()));
+        } else if fs::read(path.join(file))? != fs::read(other_path.join(file))? {
+            diffs.push(MachineDiff::Changed(file.clone()));
+        }
+    }
+    for file in other_files {
+        if !files.contains(&file) {
+            diffs.push(MachineDiff::OnlyIn(other_id.to_string(), file));
+        }
+    }
+    Ok(diffs)
+}
+
+fn list_relative_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("path: {} 不存在", dir.display()),
+        ));
+    }
+    let mut files = vec![];
+    crate::list_backup_files(dir, &mut files)?;
+    let mut files = files
+        .iter()
+        .filter_map(|file| file.strip_prefix(dir).ok())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn invalid_id() {
+        assert!(MachineProfile::new("").is_err());
+        assert!(MachineProfile::new("..").is_err());
+        assert!(MachineProfile::new("a/b").is_err());
+        assert_eq!(
+            MachineProfile::new("desktop\n").unwrap().get_id(),
+            "desktop"
+        );
+    }
+
+    #[test]
+    fn diff_machines() {
+        let base = env::temp_dir().join("auto_configuration_machine_diff");
+        let _ = fs::remove_dir_all(&base);
+        let (a, b) = (get_host_path(&base, "a"), get_host_path(&base, "b"));
+        fs::create_dir_all(a.join("etc")).unwrap();
+        fs::create_dir_all(b.join("etc")).unwrap();
+        fs::write(a.join("etc/same"), "1").unwrap();
+        fs::write(b.join("etc/same"), "1").unwrap();
+        fs::write(a.join("etc/changed"), "1").unwrap();
+        fs::write(b.join("etc/changed"), "2").unwrap();
+        fs::write(a.join("etc/only_a"), "").unwrap();
+        fs::write(b.join("etc/only_b"), "").unwrap();
+
+        let diffs = diff(&base, "a", "b").unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                MachineDiff::Changed(PathBuf::from("etc/changed")),
+                MachineDiff::OnlyIn("a".to_string(), PathBuf::from("etc/only_a")),
+                MachineDiff::OnlyIn("b".to_string(), PathBuf::from("etc/only_b")),
+            ]
+        );
+        assert!(diff(&base, "a", "none").is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,49 +1,423 @@
 extern crate notify;
+mod archive;
 mod configuration;
+mod helper;
+mod history;
+mod hook;
+mod machine;
+mod mapping;
+mod package;
+mod program;
+mod provision;
+mod reconcile;
+mod restore;
+mod retention;
+mod service;
+mod settings;
+mod storage;
+mod temp;
+mod verify;
+mod watch;
 
 use notify::{watcher, RecursiveMode, Watcher};
 use std::fs::*;
 use std::io;
+use std::io::Write;
 use std::path;
 use std::path::Path;
 use std::process::*;
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 extern crate scheduled_thread_pool;
 
 use scheduled_thread_pool::JobHandle;
 use scheduled_thread_pool::ScheduledThreadPool;
 
+use configuration::{PackageManager, PrivilegeStrategy, UninstallOptions};
+use helper::PrivilegedHelper;
+use history::Commit;
+use hook::Hooks;
+use machine::{MachineDiff, MachineProfile};
+use mapping::{BackupLayout, BackupPathMapper};
+use program::ProgramStatus;
+use restore::RestorePoint;
+use retention::RetentionPolicy;
+use service::ServiceScope;
+use settings::Settings;
+use storage::{Storage, StorageKind};
+use watch::WatchMode;
+
+const USAGE: &str = "usage: auto-configuration [-c <configuration.toml>] <command>
+
+commands:
+    watch                           监听并自动备份配置文件(默认)
+    restore [<path>...]             恢复指定路径，不指定时恢复所有备份
+    restore-machine <id>            将另一台机器的备份恢复到当前机器
+    diff-machine <id> [<other_id>]  比较两台机器的备份，other_id默认为当前机器
+    log <path>                      列出修改过path备份的提交
+    show <path> [<revision>]        输出path在revision(默认HEAD)时的备份
+    diff <path> [<rev> [<rev>]]     比较path与备份的差异：不指定rev时比较当前文件与
+                                    最新备份，指定一个时与该rev比较，两个时比较两个rev
+    restore-at [--dry-run] (--revision <rev> | --time <time>) (--config <name> | <path>...)
+                                    恢复到指定提交或时间(如\"yesterday 18:00\")时的备份，
+                                    恢复前会先提交当前状态
+    prune [--dry-run]               按retention策略合并旧的提交，精简后的历史写入retention
+                                    分支，当前分支不变
+    export [--revision <rev> | --time <time>] [--config <name>...] <archive>
+                                    将configuration(默认所有)的备份导出为tar.gz归档
+    import [--dry-run] <archive>    将export导出的归档恢复到当前机器
+    packages [--modified]           列出/etc下已备份文件所属的包，--modified时只列出
+                                    与包中默认内容不同的conffiles
+    provision [--dry-run] [--json] [<name>...]
+                                    安装[program.<name>]定义的程序(默认所有)及其依赖，
+                                    恢复配置文件并执行配置，--dry-run时只输出计划
+    programs                        列出[program.<name>]定义的程序：missing、outdated、
+                                    present(命令存在但不是通过包管理器安装)或installed
+    uninstall [--purge] [--remove-orphans] <name>
+                                    卸载[program.<name>]定义的程序的包，--purge时先备份
+                                    并提交配置文件，移除的依赖不会被purge
+    helper                          以root运行，通过stdin/stdout为没有权限的进程读写、列出
+                                    from_paths下的文件，由helper-command启动
+    service (install | unit | status) [--user | --system]
+                                    安装systemd unit、输出unit或查询服务状态，
+                                    from_paths都在$HOME下时默认为--user。--system
+                                    以sudo前的用户运行，通过helper-command读写文件
+    verify                          检查备份仓库的完整性、缺少的备份、孤立的备份文件
+                                    与hash不一致的备份";
+
 fn main() {
-    // println!("Hello, world!");
-    // let backup_base_path = Path::new("/home/navyd/tmp/.backup");
-    // let hold_duration = Duration::from_secs(60);
-    // let backup_duration = Duration::from_secs(60);
-    // let config = Configuration::new(backup_base_path, hold_duration, backup_duration);
-
-    // let paths = vec!["/home/navyd/tmp/test"];
-    // watch(&paths, config);
-    // watch()
-    let mut from_paths = HashMap::new();
-    from_paths.insert(
-        Path::new("/home/navyd/tmp/test").to_path_buf(),
-        RecursiveMode::Recursive,
-    );
-    let config = Configuration {
-        commit_duration: Duration::from_secs(5),
-        from_paths,
-        name: "test".to_string(),
-    };
-    let backup_base_path = Path::new("/home/navyd/tmp/.backup");
-    let context = BackupContext::new(vec![config], backup_base_path);
-    let server = BackupServer::new(context);
-    server.start();
-    let context = server.get_context();
-    loop {
-        thread::sleep(Duration::from_secs(2));
-        let a = context.holding_paths.lock().unwrap();
-        println!("{:?}", a)
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut config_path = PathBuf::from(settings::CONFIGURATION_FILE);
+    if args.len() >= 2 && (args[0] == "-c" || args[0] == "--config") {
+        config_path = PathBuf::from(args.remove(1));
+        args.remove(0);
+    }
+    let res = Settings::load(&config_path)
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("加载配置{}失败: {}", config_path.display(), e),
+            )
+        })
+        .and_then(|settings| run(&settings, &config_path, &args));
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(settings: &Settings, config_path: &Path, args: &[String]) -> io::Result<()> {
+    let context = settings.to_context()?;
+    let invalid_args = || io::Error::new(io::ErrorKind::InvalidInput, USAGE);
+    match args.first().map(String::as_str) {
+        None | Some("watch") => {
+            let server = BackupServer::new(context);
+            server.start();
+            if let Some(interval) = settings.program.verify_interval {
+                server.schedule_verify(Duration::from_secs(interval));
+            }
+            loop {
+                thread::sleep(service::STATUS_INTERVAL);
+                let holding_paths = server.get_context().holding_paths.lock().unwrap();
+                println!("holding paths: {:?}", holding_paths);
+                let state = format!("STATUS=holding {} paths", holding_paths.len());
+                if let Err(e) = service::notify(&state) {
+                    eprintln!("sd_notify error: {}", e);
+                }
+            }
+        }
+        Some("restore") => {
+            let restored = if args.len() == 1 {
+                context.restore_all()?
+            } else {
+                args[1..]
+                    .iter()
+                    .map(|path| context.restore(Path::new(path)))
+                    .collect::<io::Result<Vec<_>>>()?
+            };
+            for path in restored {
+                println!("已恢复 {}", path.display());
+            }
+        }
+        Some("restore-machine") => {
+            let id = args.get(1).ok_or_else(invalid_args)?;
+            for path in context.restore_machine(id)? {
+                println!("已恢复 {}", path.display());
+            }
+        }
+        Some("diff-machine") => {
+            let id = args.get(1).ok_or_else(invalid_args)?;
+            let other_id = match args.get(2) {
+                Some(other_id) => other_id.as_str(),
+                None => context
+                    .get_machine()
+                    .map(MachineProfile::get_id)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "未启用per-machine")
+                    })?,
+            };
+            for d in machine::diff(&context.backup_base_path, id, other_id)? {
+                match d {
+                    MachineDiff::OnlyIn(id, path) => println!("only in {}: {}", id, path.display()),
+                    MachineDiff::Changed(path) => println!("changed: {}", path.display()),
+                }
+            }
+        }
+        Some("log") => {
+            let path = args.get(1).ok_or_else(invalid_args)?;
+            for commit in context.log(Path::new(path))? {
+                println!("{} {} {}", commit.id, commit.date, commit.message);
+            }
+        }
+        Some("show") => {
+            let path = args.get(1).ok_or_else(invalid_args)?;
+            let revision = args.get(2).map(String::as_str).unwrap_or("HEAD");
+            io::stdout().write_all(&context.show(Path::new(path), revision)?)?;
+        }
+        Some("diff") => {
+            let path = args.get(1).ok_or_else(invalid_args)?;
+            let (from, to) = (args.get(2), args.get(3));
+            print!(
+                "{}",
+                context.diff(
+                    Path::new(path),
+                    from.map(String::as_str),
+                    to.map(String::as_str)
+                )?
+            );
+        }
+        Some("restore-at") => {
+            let (mut dry_run, mut point, mut name, mut paths) = (false, None, None, vec![]);
+            let mut iter = args[1..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--dry-run" => dry_run = true,
+                    "--revision" => {
+                        let rev = iter.next().ok_or_else(invalid_args)?;
+                        point = Some(RestorePoint::Revision(rev.to_string()));
+                    }
+                    "--time" => {
+                        let time = iter.next().ok_or_else(invalid_args)?;
+                        point = Some(RestorePoint::Time(time.to_string()));
+                    }
+                    "--config" => name = Some(iter.next().ok_or_else(invalid_args)?),
+                    path => paths.push(PathBuf::from(path)),
+                }
+            }
+            let point = point.ok_or_else(invalid_args)?;
+            let changes = match name {
+                Some(name) => context.restore_configuration_at(name, &point, dry_run)?,
+                None if !paths.is_empty() => context.restore_at(&paths, &point, dry_run)?,
+                None => return Err(invalid_args()),
+            };
+            print_changes(changes, dry_run);
+        }
+        Some("prune") => {
+            let dry_run = args.get(1).map(|arg| arg == "--dry-run").unwrap_or(false);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs();
+            let report = context.prune(now, dry_run)?;
+            for (commit, into) in &report.squashed {
+                println!(
+                    "squash {} {} {} => {}",
+                    commit.id, commit.date, commit.message, into
+                );
+            }
+            println!(
+                "kept {} commits, squashed {} commits",
+                report.kept.len(),
+                report.squashed.len()
+            );
+        }
+        Some("export") => {
+            let (mut point, mut names, mut archive) = (None, vec![], None);
+            let mut iter = args[1..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--revision" => {
+                        let rev = iter.next().ok_or_else(invalid_args)?;
+                        point = Some(RestorePoint::Revision(rev.to_string()));
+                    }
+                    "--time" => {
+                        let time = iter.next().ok_or_else(invalid_args)?;
+                        point = Some(RestorePoint::Time(time.to_string()));
+                    }
+                    "--config" => names.push(iter.next().ok_or_else(invalid_args)?.to_string()),
+                    path => archive = Some(PathBuf::from(path)),
+                }
+            }
+            let archive = archive.ok_or_else(invalid_args)?;
+            let manifest = context.export(&names, point.as_ref(), &archive)?;
+            println!(
+                "已导出{}个文件({})到 {}",
+                manifest.files.len(),
+                manifest.revision,
+                archive.display()
+            );
+        }
+        Some("import") => {
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            let archive = args[1..]
+                .iter()
+                .find(|arg| *arg != "--dry-run")
+                .ok_or_else(invalid_args)?;
+            print_changes(context.import(Path::new(archive), dry_run)?, dry_run);
+        }
+        Some("provision") => {
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            let json = args.iter().any(|arg| arg == "--json");
+            let programs = settings.get_programs()?;
+            let mut names = args[1..]
+                .iter()
+                .filter(|arg| !arg.starts_with("--"))
+                .cloned()
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                names = programs
+                    .iter()
+                    .map(|program| program.get_name().to_string())
+                    .collect();
+            }
+            let plan = provision::plan(&programs, &names)?;
+            if dry_run {
+                let steps = plan.to_steps(&context, context.privilege)?;
+                if json {
+                    println!("{}", provision::to_json(&steps));
+                } else {
+                    print!("{}", provision::render_report(&steps));
+                }
+            } else {
+                plan.execute(&context, context.privilege)?;
+            }
+        }
+        Some("uninstall") => {
+            let mut options = UninstallOptions::default();
+            let mut name = None;
+            for arg in &args[1..] {
+                match arg.as_str() {
+                    "--purge" => options.purge = true,
+                    "--remove-orphans" => options.remove_orphans = true,
+                    arg if !arg.starts_with("--") && name.is_none() => name = Some(arg),
+                    _ => return Err(invalid_args()),
+                }
+            }
+            let name = name.ok_or_else(invalid_args)?;
+            let program = settings
+                .get_programs()?
+                .into_iter()
+                .find(|program| program.get_name() == name)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("程序{}未定义", name))
+                })?;
+            let held = context.uninstall_program(program.as_ref(), options)?;
+            for path in &held {
+                println!("已备份 {}", path.display());
+            }
+            println!("已卸载{}，卸载前备份了{}个文件", name, held.len());
+        }
+        Some("programs") => {
+            for program in settings.get_programs()? {
+                println!("{} {}", program.get_name(), program.get_status()?);
+            }
+        }
+        Some("helper") => {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            helper::serve(
+                &context.configurations,
+                &mut stdin.lock(),
+                &mut stdout.lock(),
+            )?;
+        }
+        Some("service") => {
+            let scope = args
+                .get(2)
+                .map(|arg| ServiceScope::parse(arg).ok_or_else(invalid_args))
+                .transpose()?
+                .unwrap_or_else(|| ServiceScope::detect(&context.configurations));
+            match args.get(1).map(String::as_str) {
+                Some("install") => {
+                    let unit_path = service::install(
+                        scope,
+                        config_path,
+                        settings.program.helper_command.as_deref(),
+                    )?;
+                    println!("已安装并启动 {}", unit_path.display());
+                }
+                Some("unit") => {
+                    let user = match scope {
+                        ServiceScope::User => String::new(),
+                        ServiceScope::System => service::get_service_user()?,
+                    };
+                    print!(
+                        "{}",
+                        service::generate_unit(
+                            scope,
+                            &std::env::current_exe()?,
+                            &canonicalize(config_path)?,
+                            &user
+                        )
+                    );
+                    if let (ServiceScope::System, Some(command)) =
+                        (scope, &settings.program.helper_command)
+                    {
+                        print!(
+                            "\n# sudoers\n# {}",
+                            service::generate_sudoers(&user, command)?
+                        );
+                    }
+                }
+                Some("status") => {
+                    for (key, value) in service::status(scope)? {
+                        println!("{}: {}", key, value);
+                    }
+                }
+                _ => return Err(invalid_args()),
+            }
+        }
+        Some("verify") => {
+            let issues = context.verify()?;
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            if !issues.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("发现{}个问题", issues.len()),
+                ));
+            }
+        }
+        Some("packages") => {
+            let owners: Vec<_> = if args.get(1).map(String::as_str) == Some("--modified") {
+                context.list_modified_conffiles()?
+            } else {
+                context
+                    .list_packages()?
+                    .iter()
+                    .map(|(path, owner)| (path, owner.clone()))
+                    .collect()
+            };
+            for (path, owner) in owners {
+                println!("{} {} {}", path.display(), owner.package, owner.version);
+            }
+        }
+        _ => return Err(invalid_args()),
+    }
+    Ok(())
+}
+
+fn print_changes(changes: Vec<restore::RestoreChange>, dry_run: bool) {
+    for change in changes {
+        match change.diff {
+            Some(diff) => print!("{}", diff),
+            None => println!("new file: {}", change.path.display()),
+        }
+        if !dry_run {
+            println!("已恢复 {} 到 {}", change.path.display(), change.revision);
+        }
     }
 }
 
@@ -68,66 +442,188 @@ impl BackupServer {
         &self.backup_context
     }
 
+    /// 每隔interval执行一次verify并输出发现的问题
+    pub fn schedule_verify(&self, interval: Duration) -> JobHandle {
+        let context = Arc::clone(&self.backup_context);
+        self.scheduler
+            .execute_at_fixed_rate(interval, interval, move || match context.verify() {
+                Ok(issues) => {
+                    for issue in issues {
+                        eprintln!("verify: {}", issue);
+                    }
+                }
+                Err(e) => eprintln!("verify error: {}", e),
+            })
+    }
+
     pub fn start(&self) {
         let context = Arc::clone(&self.backup_context);
         let config = Arc::clone(&self.backup_context.configurations);
         let jobs = Arc::clone(&self.scheduler_jobs);
         let commit_duration = self.commit_duration.clone();
         let scheduler = Arc::clone(&self.scheduler);
+        // pre-commit hooks可能耗时较长，在单独的线程中执行，不阻塞事件处理
+        let (hold_tx, hold_rx) = channel::<PathBuf>();
+        {
+            let context = Arc::clone(&context);
+            thread::spawn(move || {
+                for path in hold_rx {
+                    hold_and_schedule(&context, &jobs, &scheduler, commit_duration, path);
+                }
+            });
+        }
         thread::spawn(move || {
             let (tx, rx) = channel();
-            let mut watcher = watcher(tx, Duration::from_secs(3)).expect("watcher start failed");
+            let mut watcher =
+                watcher(tx.clone(), Duration::from_secs(3)).expect("watcher start failed");
             for config in config.iter() {
                 for (path, mode) in &config.from_paths {
-                    if let Err(e) = watcher.watch(path, *mode) {
-                        eprintln!("{} watch error: {}", path.to_str().unwrap(), e);
+                    let native = config.watch_mode == WatchMode::Native && !watch::needs_poll(path);
+                    if native {
+                        match watcher.watch(path, *mode) {
+                            Ok(_) => continue,
+                            Err(e) => eprintln!("{} watch error: {}，改为轮询", path.display(), e),
+                        }
+                    }
+                    let helper = context.get_helper();
+                    if let Err(e) =
+                        watch::poll(path, *mode, config.poll_interval, helper, tx.clone())
+                    {
+                        eprintln!("{} poll error: {}", path.display(), e);
                     }
                 }
             }
+            // 监听开始后再比较，比较期间的修改会在之后的事件中处理
+            match context.commit_offline_changes() {
+                Ok(held) if !held.is_empty() => println!("已提交{}个离线修改", held.len()),
+                Ok(_) => {}
+                Err(e) => eprintln!("offline changes commit error: {}", e),
+            }
+            if let Err(e) = service::notify("READY=1") {
+                eprintln!("sd_notify error: {}", e);
+            }
+            // 事件循环与hold线程都在运行时才ping watchdog，卡住或退出时由systemd重启
+            let watchdog = service::get_watchdog_interval();
+            let mut last_ping = Instant::now();
+            let mut hold_alive = true;
             loop {
-                match rx.recv() {
-                    Ok(e) => {
-                        match e {
-                            notify::DebouncedEvent::Create(b)
-                            | notify::DebouncedEvent::Write(b)
-                            | notify::DebouncedEvent::Remove(b) => {
-                                let path_str = b.to_str().unwrap().to_owned();
-                                if let Err(e) = context.hold(b.as_path()) {
-                                    eprintln!("config hold error: {}", e);
-                                } else {
-                                    println!("{} 已复制", path_str);
-                                    if let Some(job) = jobs.get(&b) {
-                                        job.cancel();
-                                        println!("path: {} 之前的计时已取消", path_str);
-                                    }
-                                    let context = context.clone();
-                                    scheduler.execute_after(commit_duration, move || {
-                                        if let Err(e) = context.commit(b.as_path()) {
-                                            eprintln!("commit error: {}", e);
-                                        } else {
-                                            println!("commited path: {}", b.to_str().unwrap());
-                                        }
-                                    });
-                                    println!("已提交定时任务 path: {}", path_str);
-                                }
-                            }
-                            _ => {}
-                        };
+                let timeout = watchdog
+                    .map(|interval| interval.saturating_sub(last_ping.elapsed()))
+                    .unwrap_or(service::STATUS_INTERVAL);
+                match rx.recv_timeout(timeout) {
+                    Ok(notify::DebouncedEvent::Create(b))
+                    | Ok(notify::DebouncedEvent::Write(b))
+                    | Ok(notify::DebouncedEvent::Remove(b)) => {
+                        if let Err(e) = hold_tx.send(b) {
+                            eprintln!("hold thread exited: {}", e);
+                            hold_alive = false;
+                        }
                     }
-                    Err(e) => println!("watch error: {:?}", e),
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(e) => {
+                        println!("watch error: {:?}", e);
+                        return;
+                    }
+                }
+                match watchdog {
+                    Some(interval) if hold_alive && last_ping.elapsed() >= interval => {
+                        if let Err(e) = service::notify("WATCHDOG=1") {
+                            eprintln!("sd_notify error: {}", e);
+                        }
+                        last_ping = Instant::now();
+                    }
+                    _ => {}
                 }
             }
         });
     }
 }
 
+/// 执行pre-commit hooks并hold path，之后在commit_duration后提交
+fn hold_and_schedule(
+    context: &Arc<BackupContext>,
+    jobs: &HashMap<PathBuf, JobHandle>,
+    scheduler: &ScheduledThreadPool,
+    commit_duration: Duration,
+    path: PathBuf,
+) {
+    let path_str = path.display().to_string();
+    // 被删除的文件没有需要校验的内容
+    let held = if is_removed(&path) {
+        context.hold(&path)
+    } else {
+        context
+            .run_pre_commit_hooks(&path)
+            .and_then(|_| context.hold(&path))
+    };
+    if let Err(e) = held {
+        eprintln!("config hold error: {}", e);
+        return;
+    }
+    println!("{} 已复制", path_str);
+    if let Some(job) = jobs.get(&path) {
+        job.cancel();
+        println!("path: {} 之前的计时已取消", path_str);
+    }
+    let context = Arc::clone(context);
+    scheduler.execute_after(commit_duration, move || {
+        if let Err(e) = context.commit(&path) {
+            eprintln!("commit error: {}", e);
+        } else {
+            println!("commited path: {}", path.display());
+        }
+    });
+    println!("已提交定时任务 path: {}", path_str);
+}
+
 pub struct Configuration {
     from_paths: HashMap<PathBuf, RecursiveMode>,
     commit_duration: Duration,
     name: String,
+    /// 保存到所有机器共享的common目录而不是当前机器的目录
+    shared: bool,
+    /// 备份历史的保留策略，None时保留所有提交
+    retention: Option<RetentionPolicy>,
+    hooks: Hooks,
+    watch_mode: WatchMode,
+    /// watch_mode为Poll或退回轮询时的扫描间隔
+    poll_interval: Duration,
 }
 
-impl Configuration {}
+impl Configuration {
+    /// path是否在该configuration的from_paths下
+    pub fn contains(&self, path: &Path) -> bool {
+        let path = match mapping::normalize(path) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        self.from_paths.keys().any(|from_path| {
+            mapping::normalize(from_path)
+                .map(|from_path| path.starts_with(from_path))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+impl Configuration {
+    /// 递归监听paths的configuration，其余为默认值
+    pub fn for_test(name: &str, paths: &[&Path]) -> Configuration {
+        Configuration {
+            from_paths: paths
+                .iter()
+                .map(|path| (path.to_path_buf(), RecursiveMode::Recursive))
+                .collect(),
+            commit_duration: Duration::from_secs(1),
+            name: name.to_string(),
+            shared: false,
+            retention: None,
+            hooks: Hooks::default(),
+            watch_mode: WatchMode::default(),
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
 
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -137,135 +633,362 @@ pub struct BackupContext {
     configurations: Arc<Vec<Configuration>>,
     holding_paths: Arc<Mutex<HashMap<PathBuf, Instant>>>,
     backup_base_path: PathBuf,
+    /// 当前机器的备份映射，启用machine时base为`hosts/<id>`
+    mapper: BackupPathMapper,
+    /// 共享配置的备份映射，base为`common`，只在启用machine时存在
+    common_mapper: Option<BackupPathMapper>,
+    machine: Option<MachineProfile>,
+    storage: Box<dyn Storage>,
+    /// 等待提交的pre-commit hook结果摘要
+    hook_outputs: Mutex<HashMap<PathBuf, Vec<String>>>,
+    /// 用于查询`/etc`下文件所属的包
+    package_manager: PackageManager,
+    privilege: PrivilegeStrategy,
+    /// 读写没有权限的源文件
+    helper: Option<Arc<PrivilegedHelper>>,
 }
 
+#[cfg(test)]
 impl BackupContext {
-    pub fn new(configurations: Vec<Configuration>, backup_base_path: &Path) -> Self {
+    /// 在backup初始化git仓库，以Absolute layout备份configurations
+    pub fn for_test(configurations: Vec<Configuration>, backup: &Path) -> BackupContext {
+        history::tests::init_repo(backup);
+        BackupContext::new(
+            configurations,
+            backup,
+            BackupLayout::Absolute,
+            None,
+            StorageKind::Git,
+        )
+    }
+}
+
+impl BackupContext {
+    pub fn new(
+        configurations: Vec<Configuration>,
+        backup_base_path: &Path,
+        layout: BackupLayout,
+        machine: Option<MachineProfile>,
+        storage: StorageKind,
+    ) -> Self {
+        let (mapper, common_mapper) = match &machine {
+            Some(machine) => (
+                BackupPathMapper::new(&machine.get_host_path(backup_base_path), layout),
+                Some(BackupPathMapper::new(
+                    &machine::get_common_path(backup_base_path),
+                    layout,
+                )),
+            ),
+            None => (BackupPathMapper::new(backup_base_path, layout), None),
+        };
         BackupContext {
             configurations: Arc::new(configurations),
             backup_base_path: backup_base_path.to_path_buf(),
             holding_paths: Arc::new(Mutex::new(HashMap::new())),
+            mapper,
+            common_mapper,
+            machine,
+            storage: storage::new_storage(storage, backup_base_path),
+            hook_outputs: Mutex::new(HashMap::new()),
+            // 由Settings::to_context检测并设置
+            package_manager: PackageManager::Other,
+            privilege: PrivilegeStrategy::default(),
+            helper: None,
         }
     }
 
+    pub fn get_machine(&self) -> Option<&MachineProfile> {
+        self.machine.as_ref()
+    }
+
+    fn find_configuration(&self, path: &Path) -> Option<&Configuration> {
+        self.configurations
+            .iter()
+            .find(|config| config.contains(path))
+    }
+
+    /// 获取path所属的configuration name，不属于任何configuration时为空
+    fn get_configuration_name(&self, path: &Path) -> &str {
+        self.find_configuration(path)
+            .map(|config| config.name.as_str())
+            .unwrap_or("")
+    }
+
+    /// 获取保存path时使用的映射，shared的configuration保存到common目录
+    fn get_mapper(&self, path: &Path) -> &BackupPathMapper {
+        match (&self.common_mapper, self.find_configuration(path)) {
+            (Some(common_mapper), Some(config)) if config.shared => common_mapper,
+            _ => &self.mapper,
+        }
+    }
+
+    /// 提交path的备份，见[Self::commit_paths]
     pub fn commit(&self, path: &Path) -> io::Result<()> {
-        Command::new("git")
-            .arg("add")
-            .arg(".")
-            .current_dir(self.backup_base_path.as_path())
-            .stdout(Stdio::inherit())
-            .spawn()
-            .and_then(|mut child| child.wait())?;
-        Command::new("git")
-            .arg("commit")
-            .args(("-m 'commit_'").split(" "))
-            .current_dir(self.backup_base_path.as_path())
-            .stdout(Stdio::inherit())
-            .spawn()
-            .and_then(|mut child| child.wait())?;
+        self.commit_paths(&format!("backup {}", path.display()), &[path.to_path_buf()])?;
         Ok(())
     }
 
-    /// 尝试将from_path的文件复制保存
+    /// 提交备份目录中的所有修改，没有修改时返回false，见[Self::commit_paths]
+    pub fn commit_message(&self, message: &str) -> io::Result<bool> {
+        self.commit_paths(message, &[])
+    }
+
+    /// 以subject提交备份目录中的所有修改，没有修改时返回false
+    ///
+    /// 存储会提交备份目录中的所有文件，包括其它configuration已hold但计时尚未到期的文件，
+    /// 因此paths与所有已hold的文件都列在提交信息中，之后为它们的pre-commit hooks结果，
+    /// 提交后对它们执行post-commit hooks
+    pub fn commit_paths(&self, subject: &str, paths: &[PathBuf]) -> io::Result<bool> {
+        // 提交期间hold的文件等待下次提交，不会被提交却没有记录
+        let mut holding_paths = self.holding_paths.lock().unwrap();
+        let mut held = holding_paths
+            .keys()
+            .filter(|path| !paths.contains(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        held.sort();
+        let committed = paths.iter().cloned().chain(held).collect::<Vec<_>>();
+
+        let mut message = subject.to_string();
+        if !committed.is_empty() {
+            message.push('\n');
+        }
+        for path in &committed {
+            message.push_str(&format!("\n{}", path.display()));
+        }
+        message.push_str(&self.get_hook_log(&committed));
+        let changed = self.storage.commit(&message)?;
+        for path in &committed {
+            holding_paths.remove(path);
+        }
+        drop(holding_paths);
+
+        self.clear_hook_log(&committed);
+        if changed {
+            for path in &committed {
+                self.run_post_commit_hooks(path);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// 获取from_path对应的备份文件相对于备份仓库的路径
+    ///
+    /// 启用machine时优先使用当前机器目录中存在的备份，其次是common
+    fn get_backup_relative_path(&self, from_path: &Path) -> io::Result<PathBuf> {
+        let name = self.get_configuration_name(from_path);
+        let mut backup_path = self.get_mapper(from_path).to_backup_path(from_path, name)?;
+        for mapper in std::iter::once(&self.mapper).chain(&self.common_mapper) {
+            let path = mapper.to_backup_path(from_path, name)?;
+            if path.exists() {
+                backup_path = path;
+                break;
+            }
+        }
+        backup_path
+            .strip_prefix(&self.backup_base_path)
+            .map(Path::to_path_buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// 列出修改过from_path备份的提交，最新的在前
+    pub fn log(&self, from_path: &Path) -> io::Result<Vec<Commit>> {
+        self.storage.log(&self.get_backup_relative_path(from_path)?)
+    }
+
+    /// 获取from_path在revision时的备份内容
+    pub fn show(&self, from_path: &Path, revision: &str) -> io::Result<Vec<u8>> {
+        self.storage
+            .show(revision, &self.get_backup_relative_path(from_path)?)
+    }
+
+    /// 比较from_path的备份差异
+    ///
+    /// - from, to都为None：当前文件与最新的备份
+    /// - 只有from：当前文件与from时的备份
+    /// - from, to：两个revision之间的备份
+    pub fn diff(
+        &self,
+        from_path: &Path,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> io::Result<String> {
+        let path = self.get_backup_relative_path(from_path)?;
+        match (from, to) {
+            (Some(from), Some(to)) => self.storage.diff_revisions(from, to, &path),
+            (from, _) => self
+                .storage
+                .diff_live(from.unwrap_or("HEAD"), &path, from_path),
+        }
+    }
+
+    /// 尝试将from_path的文件复制保存，from_path已被删除时删除其备份，见[Self::hold_removed]
     ///
     /// 如果上次保存时间不超过self.hold_duration则会返回ErrorKind::Other
     ///
     /// 如果from path是一个path则会返回一个ErrorKind::InvalidInput，暂不支持目录
     pub fn hold(&self, from_path: &Path) -> std::io::Result<()> {
-        // 不支持目录
-        if !from_path.is_file() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "unsupported for path:".to_owned() + from_path.to_str().unwrap(),
-            ));
+        match metadata(from_path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.hold_removed(from_path),
+            // 不支持目录
+            Ok(metadata) if !metadata.is_file() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported for path: {}", from_path.display()),
+                ))
+            }
+            // 没有权限时由helper读取，helper只允许普通文件
+            _ => {}
         }
         let backup_path = self.get_backup_file_path(from_path)?;
-        let contents = read_to_string(from_path).unwrap();
-        write(backup_path, contents)?;
+        // 复制期间不提交，否则文件被提交但没有记录在提交信息中
+        let mut h = self.holding_paths.lock().unwrap();
+        self.copy_from_source(from_path, &backup_path)?;
+        if let Err(e) = self.record_package_owner(from_path) {
+            eprintln!("path: {} 查询所属的包失败: {}", from_path.display(), e);
+        }
+        h.insert(from_path.to_path_buf(), Instant::now());
+        Ok(())
+    }
+
+    /// 删除已被删除的from_path的备份，提交时记录为删除。没有备份时返回ErrorKind::NotFound
+    fn hold_removed(&self, from_path: &Path) -> io::Result<()> {
+        let backup_path = self
+            .get_mapper(from_path)
+            .to_backup_path(from_path, self.get_configuration_name(from_path))?;
         let mut h = self.holding_paths.lock().unwrap();
+        match remove_file(&backup_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("path: {} 没有备份", from_path.display()),
+                ))
+            }
+            Err(e) => return Err(e),
+        }
+        if let Err(e) = self.remove_package_owner(from_path) {
+            eprintln!("path: {} 移除所属的包失败: {}", from_path.display(), e);
+        }
         h.insert(from_path.to_path_buf(), Instant::now());
         Ok(())
     }
 
+    /// 将备份文件复制回from_path对应的源文件路径
+    ///
+    /// 如果from_path没有备份则返回ErrorKind::NotFound
+    ///
+    /// 启用machine时优先使用当前机器的备份，其次是common中的备份
+    pub fn restore(&self, from_path: &Path) -> io::Result<PathBuf> {
+        let name = self.get_configuration_name(from_path);
+        for mapper in std::iter::once(&self.mapper).chain(&self.common_mapper) {
+            let backup_path = mapper.to_backup_path(from_path, name)?;
+            if backup_path.is_file() {
+                return self.restore_backup_file(mapper, &backup_path);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("path: {} 没有备份", from_path.display()),
+        ))
+    }
+
+    /// 将备份目录中所有文件恢复到当前机器上对应的路径，返回恢复的源文件路径
+    ///
+    /// 路径中的占位符如`~`会按当前机器解析。启用machine时先恢复common，
+    /// 再用当前机器的备份覆盖
+    pub fn restore_all(&self) -> io::Result<Vec<PathBuf>> {
+        let mut restored = vec![];
+        if let Some(common_mapper) = &self.common_mapper {
+            restored.append(&mut self.restore_all_backup_files(common_mapper)?);
+        }
+        restored.append(&mut self.restore_all_backup_files(&self.mapper)?);
+        Ok(restored)
+    }
+
+    /// 将id对应机器的备份恢复到当前机器
+    pub fn restore_machine(&self, id: &str) -> io::Result<Vec<PathBuf>> {
+        let machine = MachineProfile::new(id)?;
+        let mapper = BackupPathMapper::new(
+            &machine.get_host_path(&self.backup_base_path),
+            self.mapper.get_layout(),
+        );
+        self.restore_all_backup_files(&mapper)
+    }
+
+    /// 恢复mapper的base目录下的所有备份文件
+    fn restore_all_backup_files(&self, mapper: &BackupPathMapper) -> io::Result<Vec<PathBuf>> {
+        let mut backup_paths = vec![];
+        if mapper.get_base_path().is_dir() {
+            list_backup_files(mapper.get_base_path(), &mut backup_paths)?;
+        }
+        backup_paths
+            .iter()
+            .map(|backup_path| self.restore_backup_file(mapper, backup_path))
+            .collect()
+    }
+
+    fn restore_backup_file(
+        &self,
+        mapper: &BackupPathMapper,
+        backup_path: &Path,
+    ) -> io::Result<PathBuf> {
+        let origin_path = mapper.to_origin_path(backup_path)?;
+        self.copy_to_source(backup_path, &origin_path)?;
+        Ok(origin_path)
+    }
+
     fn get_backup_file_path(&self, from_path: &Path) -> io::Result<path::PathBuf> {
-        // path.join()对绝对路径将替换 base_path+from+path
-        let mut path_str = String::new();
-        path_str.push_str(self.backup_base_path.to_str().unwrap());
-        let mut p = from_path.to_path_buf();
-        // create dir可能将a.txt 作为目录创建 先移除
-        let filename = p.file_name().unwrap().to_str().unwrap().to_owned();
-        p.pop();
-        path_str.push_str(p.to_str().unwrap());
-        let mut backup_path = path::PathBuf::from(path_str);
-        if !backup_path.exists() {
-            create_dir_all(backup_path.as_path())?;
-        }
-        backup_path.push(filename);
+        let backup_path = self
+            .get_mapper(from_path)
+            .to_backup_path(from_path, self.get_configuration_name(from_path))?;
+        // 只创建父目录 避免将a.txt作为目录创建
+        if let Some(parent) = backup_path.parent() {
+            if !parent.exists() {
+                create_dir_all(parent)?;
+            }
+        }
         Ok(backup_path)
     }
 }
 
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::time::Instant;
+/// 递归获取mapper的base目录dir下所有备份文件，忽略`.git`等存储使用的目录与dir下的包manifest
+///
+/// 只有base目录下的[package::PACKAGES_FILE]不是备份文件，被监听目录中的同名文件仍然需要恢复
+fn list_backup_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let manifest = dir.join(package::PACKAGES_FILE);
+    list_stored_files(dir, files)?;
+    files.retain(|path| *path != manifest);
+    Ok(())
+}
 
-#[allow(unused)]
-pub enum PackageManager {
-    AptGet,
-    Pacman,
-    Other,
+/// path是否已被删除，没有权限查看时不视为删除
+fn is_removed(path: &Path) -> bool {
+    matches!(symlink_metadata(path), Err(e) if e.kind() == io::ErrorKind::NotFound)
 }
 
-#[allow(unused)]
-impl PackageManager {
-    pub fn install(&self, name: &str) -> io::Result<()> {
-        match self {
-            AptGet => Command::new("sudo")
-                .arg("apt-get")
-                .arg("install")
-                .arg(name)
-                .spawn()?
-                .wait()
-                .and_then(|status| {
-                    if status.success() {
-                        Ok(())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("未知错误: code={}", status.code().unwrap()),
-                        ))
-                    }
-                }),
-            _ => panic!("unsupported!"),
-        }
-    }
-
-    pub fn install_multiple(&self, names: Vec<&str>) -> io::Result<()> {
-        match self {
-            AptGet => Command::new("apt-get")
-                .arg("install")
-                .args(names)
-                .spawn()?
-                .wait()
-                .and_then(|status| {
-                    if status.success() {
-                        Ok(())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("未知错误: code={}", status.code().unwrap()),
-                        ))
-                    }
-                }),
-            _ => panic!("unsupported!"),
+/// 递归获取dir下所有文件，忽略`.git`等存储使用的目录
+fn list_stored_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let ignored = storage::IGNORED_DIRS
+                .iter()
+                .any(|name| path.file_name() == Some(name.as_ref()));
+            if !ignored {
+                list_stored_files(&path, files)?;
+            }
+        } else {
+            files.push(path);
         }
     }
-
-    pub fn uninstall(&self, name: &str) -> io::Result<()> {
-        Ok(())
-    }
+    Ok(())
 }
 
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Instant;
+
 fn exec(command: &str) -> io::Result<()> {
     let comm: Vec<&str> = command.split(' ').collect();
     Command::new(comm.get(0).expect(command))
@@ -291,42 +1014,108 @@ pub trait Program {
 
     fn get_package_manager(&self) -> &PackageManager;
 
-    fn install(&self) -> io::Result<()> {
+    /// 在get_package_manager中的包名，默认为程序名，见[package::PackageNames]
+    fn get_package_names(&self) -> Vec<String> {
+        vec![self.get_name().to_string()]
+    }
+
+    fn install(&self, privilege: PrivilegeStrategy) -> io::Result<()> {
         if self.exists() {
-            Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} 已安装", self.get_name())))
-        } else {
-            self.get_package_manager().install(self.get_name())
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} 已安装", self.get_name()),
+            ));
         }
+        let names = self.get_package_names();
+        self.get_package_manager()
+            .install_multiple(names.iter().map(String::as_str).collect(), privilege)
+    }
+
+    fn config(&self) -> io::Result<()>;
+
+    /// 程序的配置文件或目录
+    fn get_configuration_paths(&self) -> Vec<PathBuf> {
+        vec![]
     }
 
-    fn uninstall(&self) -> io::Result<()> {
-        self.get_package_manager().uninstall(self.get_name())
+    /// 依赖的其它程序的名称，安装时先安装并配置这些程序
+    fn get_dependencies(&self) -> Vec<String> {
+        vec![]
     }
 
-    fn config(&self) -> io::Result<()>;
+    /// config将执行的操作，用于plan，不执行
+    fn plan_config(&self) -> Vec<provision::PlanStep> {
+        vec![]
+    }
+
+    /// exists检查的命令，默认为程序名
+    fn get_command(&self) -> &str {
+        self.get_name()
+    }
 
-    /// 通过sh command -v $name验证
+    /// 命令的最低版本，不满足时exists返回false
+    fn get_version_requirement(&self) -> Option<&program::VersionRequirement> {
+        None
+    }
+
+    /// get_command在PATH中且满足get_version_requirement，不检查是否通过包管理器安装
     fn exists(&self) -> bool {
-        Command::new("sh")
-            .arg("-c")
-            .arg("command")
-            .arg("-v")
-            .arg(self.get_name())
-            .spawn()
-            .and_then(|mut child| child.wait())
-            .map(|status| status.success())
-            .unwrap_or(false)
+        let path = match program::find_command(self.get_command()) {
+            Some(path) => path,
+            None => return false,
+        };
+        match self.get_version_requirement() {
+            Some(requirement) => requirement
+                .get_version(&path)
+                .ok()
+                .flatten()
+                .map(|version| requirement.accepts(&version))
+                .unwrap_or(false),
+            None => true,
+        }
     }
-}
 
-struct RustProgram {
-    name: String,
-}
+    /// get_package_names是否都已通过get_package_manager安装，没有包名时返回false
+    fn is_package_installed(&self) -> io::Result<bool> {
+        let names = self.get_package_names();
+        if names.is_empty() {
+            return Ok(false);
+        }
+        for name in names {
+            if !self.get_package_manager().is_installed(&name)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 
-impl RustProgram {
-    pub fn install() {
-        // openssl-sys build error
-        let prepending = "libssl-dev pkg-config";
+    /// 区分命令不存在、版本过低、命令存在但不是通过包管理器安装与已安装
+    fn get_status(&self) -> io::Result<ProgramStatus> {
+        let path = match program::find_command(self.get_command()) {
+            Some(path) => path,
+            None => return Ok(ProgramStatus::Missing),
+        };
+        if let Some(requirement) = self.get_version_requirement() {
+            let version = requirement.get_version(&path)?;
+            if !version
+                .as_deref()
+                .map(|version| requirement.accepts(version))
+                .unwrap_or(false)
+            {
+                return Ok(ProgramStatus::Outdated {
+                    path,
+                    version,
+                    minimum: requirement.get_minimum().to_string(),
+                });
+            }
+        }
+        match self.is_package_installed() {
+            Ok(true) => Ok(ProgramStatus::Installed(path)),
+            Ok(false) => Ok(ProgramStatus::Present(path)),
+            // 如PackageManager::Other，无法查询
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(ProgramStatus::Present(path)),
+            Err(e) => Err(e),
+        }
     }
 }
 
@@ -337,11 +1126,11 @@ pub struct ZshProgram {
 }
 
 impl ZshProgram {
-    pub fn new(configurations: HashMap<PathBuf, RecursiveMode>) -> Self {
+    pub fn new(configurations: HashMap<PathBuf, RecursiveMode>, pm: PackageManager) -> Self {
         ZshProgram {
             name: "zsh".to_string(),
             configurations,
-            pm: PackageManager::AptGet,
+            pm,
         }
     }
 }
@@ -358,6 +1147,10 @@ impl Program for ZshProgram {
     fn config(&self) -> io::Result<()> {
         Ok(())
     }
+
+    fn get_configuration_paths(&self) -> Vec<PathBuf> {
+        self.configurations.keys().cloned().collect()
+    }
 }
 
 #[cfg(test)]
@@ -372,17 +1165,150 @@ mod tests {
             .and_then(|mut child| child.wait())
             .map(|s| s.success())
             .unwrap_or(false);
-        assert_eq!(ZshProgram::new(HashMap::new()).exists(), zsh_exists);
+        assert_eq!(
+            ZshProgram::new(HashMap::new(), PackageManager::Other).exists(),
+            zsh_exists
+        );
     }
 
     #[test]
     fn zsh_install() {
-        let zsh = ZshProgram::new(HashMap::new());
-        let res = zsh.install();
+        let zsh = ZshProgram::new(HashMap::new(), PackageManager::Other);
+        let res = zsh.install(PrivilegeStrategy::Refuse);
         if zsh.exists() {
-            assert!(res.is_err());
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
         } else {
-            assert!(res.is_ok())
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::Unsupported);
         }
     }
+
+    #[test]
+    fn hold_and_restore() {
+        let dir = std::env::temp_dir().join("auto_configuration_hold_and_restore");
+        let _ = remove_dir_all(&dir);
+        let from_path = dir.join("src/a.txt");
+        create_dir_all(from_path.parent().unwrap()).unwrap();
+        write(&from_path, "content").unwrap();
+
+        let context = BackupContext::for_test(vec![], &dir.join("backup"));
+        context.hold(&from_path).unwrap();
+        write(&from_path, "changed").unwrap();
+        assert_eq!(context.restore(&from_path).unwrap(), from_path);
+        assert_eq!(read_to_string(&from_path).unwrap(), "content");
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_all_with_configuration_name_layout() {
+        let dir = std::env::temp_dir().join("auto_configuration_restore_all");
+        let _ = remove_dir_all(&dir);
+        let src = dir.join("src");
+        create_dir_all(&src).unwrap();
+        write(src.join("a.txt"), "a").unwrap();
+        write(src.join("b.txt"), "b").unwrap();
+        // 与包manifest同名的用户文件
+        write(src.join(package::PACKAGES_FILE), "c").unwrap();
+
+        let backup = dir.join("backup");
+        let context = BackupContext::new(
+            vec![Configuration::for_test("test", &[&src])],
+            &backup,
+            BackupLayout::ConfigurationName,
+            None,
+            StorageKind::Git,
+        );
+        context.hold(&src.join("a.txt")).unwrap();
+        context.hold(&src.join("b.txt")).unwrap();
+        context.hold(&src.join(package::PACKAGES_FILE)).unwrap();
+        assert!(backup.join("test").is_dir());
+        write(backup.join(package::PACKAGES_FILE), "").unwrap();
+
+        remove_dir_all(&src).unwrap();
+        let mut restored = context.restore_all().unwrap();
+        restored.sort();
+        let src = mapping::normalize(&src).unwrap();
+        assert_eq!(
+            restored,
+            vec![
+                src.join(package::PACKAGES_FILE),
+                src.join("a.txt"),
+                src.join("b.txt")
+            ]
+        );
+        assert_eq!(read_to_string(src.join("b.txt")).unwrap(), "b");
+        assert_eq!(
+            read_to_string(src.join(package::PACKAGES_FILE)).unwrap(),
+            "c"
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hold_with_machine_and_shared() {
+        let dir = std::env::temp_dir().join("auto_configuration_machine_shared");
+        let _ = remove_dir_all(&dir);
+        let (host_src, shared_src) = (dir.join("host"), dir.join("shared"));
+        create_dir_all(&host_src).unwrap();
+        create_dir_all(&shared_src).unwrap();
+        write(host_src.join("a.txt"), "a").unwrap();
+        write(shared_src.join("b.txt"), "b").unwrap();
+
+        let config = |name: &str, path: &Path, shared| Configuration {
+            shared,
+            ..Configuration::for_test(name, &[path])
+        };
+        let backup = dir.join("backup");
+        let context = BackupContext::new(
+            vec![
+                config("host", &host_src, false),
+                config("shared", &shared_src, true),
+            ],
+            &backup,
+            BackupLayout::ConfigurationName,
+            Some(MachineProfile::new("desktop").unwrap()),
+            StorageKind::Git,
+        );
+        context.hold(&host_src.join("a.txt")).unwrap();
+        context.hold(&shared_src.join("b.txt")).unwrap();
+        assert!(backup.join("hosts/desktop/host").is_dir());
+        assert!(backup.join("common/shared").is_dir());
+
+        remove_dir_all(&host_src).unwrap();
+        remove_dir_all(&shared_src).unwrap();
+        assert_eq!(context.restore_all().unwrap().len(), 2);
+        assert_eq!(read_to_string(shared_src.join("b.txt")).unwrap(), "b");
+        remove_dir_all(&host_src).unwrap();
+        assert_eq!(context.restore_machine("desktop").unwrap().len(), 1);
+        assert_eq!(read_to_string(host_src.join("a.txt")).unwrap(), "a");
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_and_diff_with_from_path() {
+        let dir = std::env::temp_dir().join("auto_configuration_context_history");
+        let backup = dir.join("backup");
+        let context = BackupContext::for_test(vec![], &backup);
+        let from_path = dir.join("src/a.txt");
+        create_dir_all(from_path.parent().unwrap()).unwrap();
+
+        for content in &["1\n", "2\n"] {
+            write(&from_path, content).unwrap();
+            context.hold(&from_path).unwrap();
+            context.commit(&from_path).unwrap();
+        }
+        let commits = context.log(&from_path).unwrap();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(context.show(&from_path, &commits[1].id).unwrap(), b"1\n");
+
+        write(&from_path, "3\n").unwrap();
+        assert!(context
+            .diff(&from_path, None, None)
+            .unwrap()
+            .contains("-2\n+3\n"));
+        let diff = context
+            .diff(&from_path, Some(&commits[1].id), Some(&commits[0].id))
+            .unwrap();
+        assert!(diff.contains("-1\n+2\n"));
+        remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,308 @@
+use serde::Deserialize;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// `$HOME`在备份目录中的占位符
+pub const HOME_PLACEHOLDER: &str = "~";
+
+/// [BackupLayout::ConfigurationName]时不属于任何configuration的文件所在的目录，
+/// 如uninstall --purge时程序的配置文件
+pub const UNCONFIGURED_DIR: &str = "_unconfigured";
+
+/// 备份目录的布局方式
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupLayout {
+    /// 在base_path下镜像源文件的绝对路径：`/home/a/.zshrc` => `$base/home/a/.zshrc`
+    #[default]
+    Absolute,
+    /// 按configuration name分目录：`/etc/my.cnf` => `$base/mysql/etc/my.cnf`
+    ConfigurationName,
+    /// `$HOME`下的文件用占位符代替：`/home/a/.zshrc` => `$base/~/.zshrc`，
+    /// 其它路径与[BackupLayout::Absolute]一致
+    HomeRelative,
+}
+
+/// 源文件路径与备份路径之间的映射
+///
+/// 所有操作都基于`Path`的components完成，不要求路径是UTF-8
+pub struct BackupPathMapper {
+    base_path: PathBuf,
+    layout: BackupLayout,
+    /// 当前机器的`$HOME`，用于替换[HOME_PLACEHOLDER]
+    home_path: Option<PathBuf>,
+}
+
+impl BackupPathMapper {
+    pub fn new(base_path: &Path, layout: BackupLayout) -> Self {
+        BackupPathMapper {
+            base_path: base_path.to_path_buf(),
+            layout,
+            home_path: env::var_os("HOME").and_then(|home| normalize(Path::new(&home)).ok()),
+        }
+    }
+
+    pub fn get_base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    pub fn get_layout(&self) -> BackupLayout {
+        self.layout
+    }
+
+    /// 将from_path映射为base_path下的备份路径
+    ///
+    /// from_path会先通过[normalize]转为绝对路径，再按layout映射。config_name为from_path
+    /// 所属的configuration，在[BackupLayout::ConfigurationName]时作为第一级目录，
+    /// 为空时使用[UNCONFIGURED_DIR]
+    pub fn to_backup_path(&self, from_path: &Path, config_name: &str) -> io::Result<PathBuf> {
+        let from_path = normalize(from_path)?;
+        let mut backup_path = self.base_path.clone();
+        let rel = match self.layout {
+            BackupLayout::Absolute => from_path.as_path(),
+            BackupLayout::ConfigurationName => {
+                let config_name = if config_name.is_empty() {
+                    UNCONFIGURED_DIR
+                } else {
+                    config_name
+                };
+                if Path::new(config_name).components().count() != 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("非法的configuration name: {}", config_name),
+                    ));
+                }
+                backup_path.push(config_name);
+                from_path.as_path()
+            }
+            BackupLayout::HomeRelative => match self
+                .home_path
+                .as_ref()
+                .and_then(|home| from_path.strip_prefix(home).ok())
+            {
+                Some(rel) => {
+                    backup_path.push(HOME_PLACEHOLDER);
+                    rel
+                }
+                None => from_path.as_path(),
+            },
+        };
+        for comp in rel.components() {
+            if let Component::Normal(name) = comp {
+                backup_path.push(name);
+            }
+        }
+        Ok(backup_path)
+    }
+
+    /// [to_backup_path]的逆映射，将base_path下的备份路径还原为当前机器上的源文件路径
+    ///
+    /// [HOME_PLACEHOLDER]会被替换为当前的`$HOME`。
+    /// 如果backup_path不在base_path下则返回ErrorKind::InvalidInput
+    pub fn to_origin_path(&self, backup_path: &Path) -> io::Result<PathBuf> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("非法的备份路径: {}", backup_path.display()),
+            )
+        };
+        let rel = backup_path.strip_prefix(&self.base_path).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "path: {} 不在备份目录 {} 下",
+                    backup_path.display(),
+                    self.base_path.display()
+                ),
+            )
+        })?;
+        let mut comps = rel.components().peekable();
+        if self.layout == BackupLayout::ConfigurationName {
+            comps.next().ok_or_else(invalid)?;
+        }
+        let mut origin_path = PathBuf::from("/");
+        if self.layout == BackupLayout::HomeRelative
+            && comps.peek() == Some(&Component::Normal(HOME_PLACEHOLDER.as_ref()))
+        {
+            comps.next();
+            origin_path = self
+                .home_path
+                .clone()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "无法获取当前的$HOME"))?;
+        }
+        for comp in comps {
+            match comp {
+                Component::Normal(name) => origin_path.push(name),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(origin_path)
+    }
+}
+
+/// 将path转为不含`.`与`..`的绝对路径
+///
+/// - 相对路径基于当前工作目录
+/// - 如果父目录存在则通过canonicalize解析其中的符号链接，文件名本身保持不变，
+///   即对符号链接文件备份的是链接所在路径
+/// - 父目录不存在时(如已被删除)只做词法上的处理
+pub fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
+    let path = lexical_normalize(&path);
+    let file_name: OsString = match path.file_name() {
+        Some(name) => name.to_os_string(),
+        // 只剩下根目录
+        None => return Ok(path),
+    };
+    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+    let parent = parent
+        .canonicalize()
+        .unwrap_or_else(|_| parent.to_path_buf());
+    Ok(parent.join(file_name))
+}
+
+/// 在不访问文件系统的情况下移除`.`并回退`..`
+fn lexical_normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                // 根目录的..还是根目录
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn mapper() -> BackupPathMapper {
+        BackupPathMapper::new(Path::new("/base/.backup"), BackupLayout::Absolute)
+    }
+
+    fn mapper_with(layout: BackupLayout) -> BackupPathMapper {
+        BackupPathMapper {
+            base_path: PathBuf::from("/base/.backup"),
+            layout,
+            home_path: Some(PathBuf::from("/none_dir_test/home/navyd")),
+        }
+    }
+
+    #[test]
+    fn absolute_path() {
+        let backup = mapper()
+            .to_backup_path(Path::new("/none_dir_test/a/b.txt"), "test")
+            .unwrap();
+        assert_eq!(backup, Path::new("/base/.backup/none_dir_test/a/b.txt"));
+    }
+
+    #[test]
+    fn parent_dir_and_cur_dir() {
+        let backup = mapper()
+            .to_backup_path(Path::new("/none_dir_test/./a/../../../b/c.txt"), "test")
+            .unwrap();
+        assert_eq!(backup, Path::new("/base/.backup/b/c.txt"));
+    }
+
+    #[test]
+    fn relative_path() {
+        let cur = env::current_dir().unwrap().canonicalize().unwrap();
+        let backup = mapper()
+            .to_backup_path(Path::new("Cargo.toml"), "test")
+            .unwrap();
+        assert_eq!(
+            mapper().to_origin_path(&backup).unwrap(),
+            cur.join("Cargo.toml")
+        );
+    }
+
+    #[test]
+    fn non_utf8_path() {
+        let name = OsStr::from_bytes(b"a\xff\xfe.txt");
+        let from = Path::new("/none_dir_test").join(name);
+        let backup = mapper().to_backup_path(&from, "test").unwrap();
+        assert_eq!(backup.file_name(), Some(name));
+        assert_eq!(mapper().to_origin_path(&backup).unwrap(), from);
+    }
+
+    #[test]
+    fn symlinked_parent() {
+        let dir = env::temp_dir().join("auto_configuration_mapping_symlink");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+
+        let real = mapper()
+            .to_backup_path(&dir.join("real/a.txt"), "test")
+            .unwrap();
+        let link = mapper()
+            .to_backup_path(&dir.join("link/a.txt"), "test")
+            .unwrap();
+        assert_eq!(real, link);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn configuration_name_layout() {
+        let mapper = mapper_with(BackupLayout::ConfigurationName);
+        let from = Path::new("/none_dir_test/etc/my.cnf");
+        let backup = mapper.to_backup_path(from, "mysql").unwrap();
+        assert_eq!(
+            backup,
+            Path::new("/base/.backup/mysql/none_dir_test/etc/my.cnf")
+        );
+        assert_eq!(mapper.to_origin_path(&backup).unwrap(), from);
+        assert!(mapper.to_backup_path(from, "../a").is_err());
+        let backup = mapper.to_backup_path(from, "").unwrap();
+        assert_eq!(
+            backup,
+            Path::new("/base/.backup/_unconfigured/none_dir_test/etc/my.cnf")
+        );
+        assert_eq!(mapper.to_origin_path(&backup).unwrap(), from);
+    }
+
+    #[test]
+    fn home_relative_layout() {
+        let mapper = mapper_with(BackupLayout::HomeRelative);
+        let from = Path::new("/none_dir_test/home/navyd/.zshrc");
+        let backup = mapper.to_backup_path(from, "zsh").unwrap();
+        assert_eq!(backup, Path::new("/base/.backup/~/.zshrc"));
+        assert_eq!(mapper.to_origin_path(&backup).unwrap(), from);
+
+        // 在另一个用户的机器上恢复
+        let other = BackupPathMapper {
+            home_path: Some(PathBuf::from("/none_dir_test/home/other")),
+            ..mapper_with(BackupLayout::HomeRelative)
+        };
+        assert_eq!(
+            other.to_origin_path(&backup).unwrap(),
+            Path::new("/none_dir_test/home/other/.zshrc")
+        );
+
+        // 不在$HOME下的路径保持绝对路径
+        let from = Path::new("/none_dir_test/etc/my.cnf");
+        let backup = mapper.to_backup_path(from, "mysql").unwrap();
+        assert_eq!(backup, Path::new("/base/.backup/none_dir_test/etc/my.cnf"));
+        assert_eq!(mapper.to_origin_path(&backup).unwrap(), from);
+    }
+
+    #[test]
+    fn origin_path_outside_base() {
+        let res = mapper().to_origin_path(Path::new("/other/a.txt"));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,837 @@
+use crate::configuration::{CommandLookup, PackageManager, UninstallOptions};
+use crate::program;
+use crate::reconcile::list_source_files;
+use crate::{BackupContext, Program};
+
+use notify::RecursiveMode;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// 记录备份文件所属包的manifest，保存在备份目录(启用machine时为`hosts/<id>`)下
+pub const PACKAGES_FILE: &str = ".packages.toml";
+
+/// 只查询该目录下文件所属的包
+const PACKAGE_CONFIG_DIR: &str = "/etc";
+
+/// 发行版的标识
+const OS_RELEASE_FILE: &str = "/etc/os-release";
+
+/// 系统包管理器与其命令，没有发行版信息时按该顺序查找
+const SYSTEM_MANAGERS: [(PackageManager, &str); 5] = [
+    (PackageManager::AptGet, "apt-get"),
+    (PackageManager::Pacman, "pacman"),
+    (PackageManager::Dnf, "dnf"),
+    (PackageManager::Zypper, "zypper"),
+    (PackageManager::Apk, "apk"),
+];
+
+/// `rpm -q`的输出格式，多个架构的包时每行一个
+const RPM_QUERY_FORMAT: &str = "%{NAME}\t%{VERSION}-%{RELEASE}\n";
+
+/// 拥有某个文件的包
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageOwner {
+    pub package: String,
+    pub version: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageManifest {
+    /// 源文件路径 => 所属的包，路径由[encode_path]编码
+    #[serde(default)]
+    files: BTreeMap<String, PackageOwner>,
+}
+
+/// `[program.<name>]`中的packages，所有包管理器共用的包名或各包管理器中的包名
+///
+/// ```toml
+/// [program.rust]
+/// packages = { apt-get = ["cargo", "libssl-dev", "pkg-config"], dnf = ["cargo", "openssl-devel"] }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum PackageNames {
+    All(PackageNameList),
+    ByManager(HashMap<String, PackageNameList>),
+}
+
+/// 一个或多个包名
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum PackageNameList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl PackageNameList {
+    fn to_vec(&self) -> Vec<String> {
+        match self {
+            PackageNameList::One(name) => vec![name.clone()],
+            PackageNameList::Many(names) => names.clone(),
+        }
+    }
+}
+
+impl PackageNames {
+    /// 在pm中的包名，按包管理器配置且没有pm时返回None
+    pub fn get(&self, pm: &PackageManager) -> Option<Vec<String>> {
+        match self {
+            PackageNames::All(names) => Some(names.to_vec()),
+            PackageNames::ByManager(names) => names.get(pm.get_name()).map(PackageNameList::to_vec),
+        }
+    }
+}
+
+impl PackageManifest {
+    /// path不存在时返回空的manifest
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PackageManifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let content = toml::to_string(self).map_err(io::Error::other)?;
+        fs::write(path, content)
+    }
+
+    pub fn insert(&mut self, path: &Path, owner: PackageOwner) -> Option<PackageOwner> {
+        self.files.insert(encode_path(path), owner)
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<PackageOwner> {
+        self.files.remove(&encode_path(path))
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PathBuf, &PackageOwner)> {
+        self.files
+            .iter()
+            .map(|(key, owner)| (decode_path(key), owner))
+    }
+}
+
+/// toml的key只能是UTF-8，非UTF-8的字节与`%`编码为`%XX`，其余字符保持不变
+fn encode_path(path: &Path) -> String {
+    let mut key = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '%' {
+                key.push_str("%25");
+            } else {
+                key.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            key.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    key
+}
+
+/// [encode_path]的逆操作，不是合法编码的`%`保持不变
+fn decode_path(key: &str) -> PathBuf {
+    let bytes = key.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(decoded))
+}
+
+impl PackageManager {
+    /// 根据`/etc/os-release`的`ID`与`ID_LIKE`判断，命令不存在时按PATH中存在的命令判断
+    ///
+    /// 都不存在时返回ErrorKind::NotFound
+    pub fn detect() -> io::Result<PackageManager> {
+        let os_release = fs::read_to_string(OS_RELEASE_FILE).unwrap_or_default();
+        detect_from(&os_release, command_exists).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "未找到支持的包管理器(apt-get/pacman/dnf/zypper/apk)，\
+                 可在configuration.toml的[program]中设置package-manager",
+            )
+        })
+    }
+
+    /// 查询拥有path的包，不属于任何包时返回None
+    pub fn find_owner(&self, path: &Path) -> io::Result<Option<PackageOwner>> {
+        match self {
+            PackageManager::AptGet => {
+                let out = match query(Command::new("dpkg-query").arg("-S").arg(path))? {
+                    Some(out) => out,
+                    None => return Ok(None),
+                };
+                let package = match parse_dpkg_search(&out, path) {
+                    Some(package) => package,
+                    None => return Ok(None),
+                };
+                let version = query(
+                    Command::new("dpkg-query")
+                        .args(["-W", "-f=${Version}"])
+                        .arg(&package),
+                )?
+                .unwrap_or_default();
+                Ok(Some(PackageOwner { package, version }))
+            }
+            PackageManager::Pacman => Ok(query(Command::new("pacman").arg("-Qo").arg(path))?
+                .and_then(|out| parse_pacman_owner(&out))),
+            PackageManager::Dnf | PackageManager::Zypper => Ok(query(
+                Command::new("rpm")
+                    .args(["-qf", "--qf", RPM_QUERY_FORMAT])
+                    .arg(path),
+            )?
+            .and_then(|out| parse_rpm_query(&out))),
+            PackageManager::Apk => Ok(query(
+                Command::new("apk").args(["info", "--who-owns"]).arg(path),
+            )?
+            .and_then(|out| parse_apk_owner(&out))),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// 已安装的包name的版本，未安装时返回None
+    pub fn installed_version(&self, name: &str) -> io::Result<Option<String>> {
+        self.installed_version_with(CommandLookup::system(), name)
+    }
+
+    pub(crate) fn installed_version_with(
+        &self,
+        lookup: CommandLookup,
+        name: &str,
+    ) -> io::Result<Option<String>> {
+        let version = match self {
+            PackageManager::AptGet => query(
+                lookup
+                    .command("dpkg-query")
+                    .args(["-W", "-f=${Status}\t${Version}"])
+                    .arg(name),
+            )?
+            .and_then(|out| parse_dpkg_status(&out)),
+            PackageManager::Pacman => query(lookup.command("pacman").arg("-Q").arg(name))?
+                .and_then(|out| parse_name_versions(&out, name)),
+            PackageManager::Dnf | PackageManager::Zypper => query(
+                lookup
+                    .command("rpm")
+                    .args(["-q", "--qf", RPM_QUERY_FORMAT])
+                    .arg(name),
+            )?
+            .and_then(|out| parse_rpm_query(&out))
+            .map(|owner| owner.version),
+            PackageManager::Apk => query(
+                lookup
+                    .command("apk")
+                    .args(["list", "--installed"])
+                    .arg(name),
+            )?
+            .and_then(|out| parse_apk_list(&out, name)),
+            PackageManager::Cargo => query(lookup.command("cargo").args(["install", "--list"]))?
+                .and_then(|out| parse_cargo_list(&out, name)),
+            PackageManager::Pipx => query(lookup.command("pipx").args(["list", "--short"]))?
+                .and_then(|out| parse_name_versions(&out, name)),
+            // 存在缺失的peer依赖等问题时退出码也不为0
+            PackageManager::Npm => lookup
+                .command("npm")
+                .args(["ls", "-g", "--depth=0", "--parseable", "--long"])
+                .stderr(Stdio::null())
+                .output()
+                .map(|out| parse_npm_list(&String::from_utf8_lossy(&out.stdout), name))?,
+            PackageManager::Other => return Err(unsupported()),
+        };
+        Ok(version)
+    }
+
+    /// 不再被依赖的包，目前只用于pacman在purge之后单独移除依赖
+    pub(crate) fn list_orphans_with(&self, lookup: CommandLookup) -> io::Result<Vec<String>> {
+        match self {
+            // 没有时退出码为1
+            PackageManager::Pacman => Ok(query(lookup.command("pacman").arg("-Qdtq"))?
+                .map(|out| out.lines().map(str::to_string).collect())
+                .unwrap_or_default()),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// 列出与包中默认内容不同的conffiles
+    pub fn list_modified_conffiles(&self) -> io::Result<Vec<PathBuf>> {
+        match self {
+            // 存在差异的文件也会使退出码不为0
+            PackageManager::AptGet => Command::new("dpkg")
+                .arg("--verify")
+                .stderr(Stdio::null())
+                .output()
+                .map(|out| parse_dpkg_verify(&String::from_utf8_lossy(&out.stdout))),
+            PackageManager::Pacman => query(Command::new("pacman").arg("-Qii"))
+                .map(|out| parse_pacman_modified(&out.unwrap_or_default())),
+            _ => Err(unsupported()),
+        }
+    }
+}
+
+/// os_release中ID与ID_LIKE对应的包管理器且命令存在时优先使用
+fn detect_from(os_release: &str, exists: impl Fn(&str) -> bool) -> Option<PackageManager> {
+    let by_id = parse_os_ids(os_release)
+        .iter()
+        .filter_map(|id| match id.as_str() {
+            "debian" | "ubuntu" => Some(PackageManager::AptGet),
+            "arch" | "manjaro" => Some(PackageManager::Pacman),
+            "fedora" | "rhel" | "centos" => Some(PackageManager::Dnf),
+            "opensuse" | "suse" | "sles" => Some(PackageManager::Zypper),
+            "alpine" => Some(PackageManager::Apk),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    by_id
+        .iter()
+        .chain(SYSTEM_MANAGERS.iter().map(|(pm, _)| pm))
+        .copied()
+        .find(|pm| {
+            SYSTEM_MANAGERS
+                .iter()
+                .any(|(manager, command)| manager == pm && exists(command))
+        })
+}
+
+/// os-release中的`ID`与`ID_LIKE`，如`ID=opensuse-tumbleweed`时也包含`opensuse`
+fn parse_os_ids(os_release: &str) -> Vec<String> {
+    let mut ids = vec![];
+    for line in os_release.lines() {
+        let value = match line
+            .strip_prefix("ID=")
+            .or_else(|| line.strip_prefix("ID_LIKE="))
+        {
+            Some(value) => value.trim().trim_matches(|c| c == '"' || c == '\''),
+            None => continue,
+        };
+        for id in value.split_whitespace() {
+            ids.push(id.to_string());
+            if let Some((prefix, _)) = id.split_once('-') {
+                ids.push(prefix.to_string());
+            }
+        }
+    }
+    ids
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "未找到支持的包管理器")
+}
+
+pub(crate) fn command_exists(name: &str) -> bool {
+    program::find_command(name).is_some()
+}
+
+/// 执行查询命令，退出码为1(未找到)时返回None
+fn query(command: &mut Command) -> io::Result<Option<String>> {
+    let out = command.output()?;
+    match out.status.code() {
+        Some(0) => Ok(Some(String::from_utf8_lossy(&out.stdout).to_string())),
+        Some(1) => Ok(None),
+        code => Err(io::Error::other(format!(
+            "查询包失败: code={:?}, {}",
+            code,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ))),
+    }
+}
+
+/// 解析`dpkg-query -S`的输出，如`mysql-common: /etc/mysql/my.cnf`，多个包时取第一个
+fn parse_dpkg_search(out: &str, path: &Path) -> Option<String> {
+    let suffix = format!(": {}", path.display());
+    out.lines()
+        .filter(|line| !line.starts_with("diversion "))
+        .find_map(|line| line.strip_suffix(&suffix))
+        .and_then(|packages| packages.split(", ").next())
+        .map(str::to_string)
+}
+
+/// 解析`pacman -Qo`的输出，如`/etc/pacman.conf is owned by pacman 6.0.1-1`
+fn parse_pacman_owner(out: &str) -> Option<PackageOwner> {
+    let (_, owner) = out.trim().rsplit_once(" is owned by ")?;
+    let (package, version) = owner.split_once(' ')?;
+    Some(PackageOwner {
+        package: package.to_string(),
+        version: version.to_string(),
+    })
+}
+
+/// 解析`dpkg-query -W -f=${Status}\t${Version}`的输出，只保留完整安装的包
+fn parse_dpkg_status(out: &str) -> Option<String> {
+    let (status, version) = out.trim().split_once('\t')?;
+    if status.ends_with(" installed") {
+        Some(version.to_string())
+    } else {
+        None
+    }
+}
+
+/// 解析`pacman -Q`与`pipx list --short`中`<name> <version>`的行
+fn parse_name_versions(out: &str, name: &str) -> Option<String> {
+    out.lines()
+        .filter_map(|line| line.trim().split_once(' '))
+        .find(|(package, _)| *package == name)
+        .map(|(_, version)| version.trim().to_string())
+}
+
+/// 解析[RPM_QUERY_FORMAT]的输出，多个架构时取第一个
+fn parse_rpm_query(out: &str) -> Option<PackageOwner> {
+    let (package, version) = out.lines().next()?.split_once('\t')?;
+    Some(PackageOwner {
+        package: package.to_string(),
+        version: version.to_string(),
+    })
+}
+
+/// 拆分apk的`<name>-<version>-r<n>`，name中也可能包含`-`
+fn split_apk_package(s: &str) -> Option<PackageOwner> {
+    let reg = Regex::new(r"^(.+?)-(\d[^-]*-r\d+)$").unwrap();
+    let cap = reg.captures(s)?;
+    Some(PackageOwner {
+        package: cap[1].to_string(),
+        version: cap[2].to_string(),
+    })
+}
+
+/// 解析`apk info --who-owns`的输出，如`/etc/motd is owned by alpine-baselayout-3.4.3-r1`
+fn parse_apk_owner(out: &str) -> Option<PackageOwner> {
+    let (_, package) = out.trim().rsplit_once(" is owned by ")?;
+    split_apk_package(package)
+}
+
+/// 解析`apk list --installed`的输出，如`zsh-5.9-r2 x86_64 {zsh} (MIT) [installed]`
+fn parse_apk_list(out: &str, name: &str) -> Option<String> {
+    out.lines()
+        .filter_map(|line| split_apk_package(line.split_whitespace().next()?))
+        .find(|owner| owner.package == name)
+        .map(|owner| owner.version)
+}
+
+/// 解析`cargo install --list`的输出，如`ripgrep v13.0.0:`，之后缩进的行为安装的二进制
+fn parse_cargo_list(out: &str, name: &str) -> Option<String> {
+    out.lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next()? != name {
+                return None;
+            }
+            let version = fields.next()?.trim_end_matches(':');
+            Some(version.strip_prefix('v').unwrap_or(version).to_string())
+        })
+}
+
+/// 解析`npm ls -g --parseable --long`的输出，如`/usr/lib/node_modules/typescript:typescript@5.0.4`
+fn parse_npm_list(out: &str, name: &str) -> Option<String> {
+    out.lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter_map(|(_, package)| package.rsplit_once('@'))
+        .find(|(package, _)| *package == name)
+        .map(|(_, version)| version.to_string())
+}
+
+/// 解析`dpkg --verify`的输出，如`??5?????? c /etc/mysql/my.cnf`，只保留md5不同的conffile
+fn parse_dpkg_verify(out: &str) -> Vec<PathBuf> {
+    let reg = Regex::new(r"^\S{2}5\S{6} c (/.+)$").unwrap();
+    out.lines()
+        .filter_map(|line| reg.captures(line))
+        .map(|cap| PathBuf::from(&cap[1]))
+        .collect()
+}
+
+/// 解析`pacman -Qii`中Backup Files的`<path>\tMODIFIED`
+fn parse_pacman_modified(out: &str) -> Vec<PathBuf> {
+    let reg = Regex::new(r"(/[^\t]+)\tMODIFIED$").unwrap();
+    out.lines()
+        .filter_map(|line| reg.captures(line))
+        .map(|cap| PathBuf::from(&cap[1]))
+        .collect()
+}
+
+impl BackupContext {
+    pub(crate) fn get_packages_path(&self) -> PathBuf {
+        self.mapper.get_base_path().join(PACKAGES_FILE)
+    }
+
+    /// 将`/etc`下from_path所属的包记录到[PACKAGES_FILE]中，不属于任何包时从中移除
+    pub fn record_package_owner(&self, from_path: &Path) -> io::Result<()> {
+        if !from_path.starts_with(PACKAGE_CONFIG_DIR) || !self.package_manager.is_system() {
+            return Ok(());
+        }
+        let owner = self.package_manager.find_owner(from_path)?;
+        let path = self.get_packages_path();
+        let mut manifest = PackageManifest::load(&path)?;
+        let changed = match owner {
+            Some(owner) => manifest.insert(from_path, owner.clone()) != Some(owner),
+            None => manifest.remove(from_path).is_some(),
+        };
+        if changed {
+            fs::create_dir_all(self.mapper.get_base_path())?;
+            manifest.save(&path)?;
+        }
+        Ok(())
+    }
+
+    /// from_path被删除时从[PACKAGES_FILE]中移除
+    pub fn remove_package_owner(&self, from_path: &Path) -> io::Result<()> {
+        let path = self.get_packages_path();
+        let mut manifest = PackageManifest::load(&path)?;
+        if manifest.remove(from_path).is_some() {
+            manifest.save(&path)?;
+        }
+        Ok(())
+    }
+
+    pub fn list_packages(&self) -> io::Result<PackageManifest> {
+        PackageManifest::load(&self.get_packages_path())
+    }
+
+    /// 卸载program的包，purge时先通过[Self::backup_program_files]备份，备份失败时不卸载。
+    /// 返回被备份的文件
+    pub fn uninstall_program(
+        &self,
+        program: &dyn Program,
+        options: UninstallOptions,
+    ) -> io::Result<Vec<PathBuf>> {
+        let names = program.get_package_names();
+        if names.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} 没有可以卸载的包", program.get_name()),
+            ));
+        }
+        let held = if options.purge {
+            self.backup_program_files(program)?
+        } else {
+            vec![]
+        };
+        program.get_package_manager().uninstall_multiple(
+            names.iter().map(String::as_str).collect(),
+            options,
+            self.privilege,
+        )?;
+        Ok(held)
+    }
+
+    /// hold并提交program的配置文件与[PACKAGES_FILE]中属于该包的文件，返回被备份的文件
+    pub fn backup_program_files(&self, program: &dyn Program) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for path in program.get_configuration_paths() {
+            list_source_files(&path, RecursiveMode::Recursive, &mut files)?;
+        }
+        let names = program.get_package_names();
+        for (path, owner) in self.list_packages()?.iter() {
+            if names.contains(&owner.package) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        files.dedup();
+        files.retain(|path| path.is_file());
+
+        for path in &files {
+            self.hold(path)?;
+        }
+        if !files.is_empty() {
+            self.commit_paths(&format!("before uninstall {}", program.get_name()), &files)?;
+        }
+        Ok(files)
+    }
+
+    /// 列出已备份且与包中默认内容不同的conffiles
+    pub fn list_modified_conffiles(&self) -> io::Result<Vec<(PathBuf, PackageOwner)>> {
+        let mut manifest = self.list_packages()?;
+        Ok(self
+            .package_manager
+            .list_modified_conffiles()?
+            .into_iter()
+            .filter_map(|path| {
+                let owner = manifest.remove(&path)?;
+                Some((path, owner))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history;
+    use crate::mapping::BackupLayout;
+    use crate::storage::StorageKind;
+    use std::env;
+    use std::ffi::OsStr;
+
+    struct TestProgram {
+        paths: Vec<PathBuf>,
+    }
+
+    impl Program for TestProgram {
+        fn get_name(&self) -> &str {
+            "test-program"
+        }
+
+        fn get_package_manager(&self) -> &PackageManager {
+            &PackageManager::Other
+        }
+
+        fn config(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn get_configuration_paths(&self) -> Vec<PathBuf> {
+            self.paths.clone()
+        }
+    }
+
+    #[test]
+    fn backup_before_purge() {
+        let dir = env::temp_dir().join("auto_configuration_uninstall");
+        let backup = dir.join("backup");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.conf"), "a").unwrap();
+        let context = BackupContext::for_test(vec![], &backup);
+        let program = TestProgram {
+            paths: vec![src.clone(), dir.join("none")],
+        };
+        let count = || history::git(&backup, ["rev-list", "--all", "--count"]).unwrap();
+
+        // 不purge时不备份
+        assert!(context
+            .uninstall_program(&program, UninstallOptions::default())
+            .is_err());
+        assert_eq!(count(), b"0\n");
+
+        let options = UninstallOptions {
+            purge: true,
+            remove_orphans: false,
+        };
+        let err = context.uninstall_program(&program, options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let subject = history::git(&backup, ["log", "-1", "--format=%s"]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&subject).trim(),
+            "before uninstall test-program"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backup_unconfigured_program_files() {
+        let dir = env::temp_dir().join("auto_configuration_uninstall_unconfigured");
+        let backup = dir.join("backup");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.conf"), "a").unwrap();
+        history::tests::init_repo(&backup);
+        let context = BackupContext::new(
+            vec![],
+            &backup,
+            BackupLayout::ConfigurationName,
+            None,
+            StorageKind::Git,
+        );
+        let program = TestProgram {
+            paths: vec![src.clone()],
+        };
+        // 不属于任何configuration的文件备份到UNCONFIGURED_DIR下
+        assert_eq!(
+            context.backup_program_files(&program).unwrap(),
+            vec![src.join("a.conf")]
+        );
+        let backup_path = context.get_backup_file_path(&src.join("a.conf")).unwrap();
+        assert!(backup_path.starts_with(backup.join(crate::mapping::UNCONFIGURED_DIR)));
+        assert_eq!(fs::read(&backup_path).unwrap(), b"a");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_dpkg() {
+        let path = Path::new("/etc/mysql/my.cnf");
+        assert_eq!(
+            parse_dpkg_search("mysql-common, mariadb-common: /etc/mysql/my.cnf\n", path),
+            Some("mysql-common".to_string())
+        );
+        assert_eq!(
+            parse_dpkg_search(
+                "diversion by x from: /etc/mysql/my.cnf\nlibc6:amd64: /etc/mysql/my.cnf\n",
+                path
+            ),
+            Some("libc6:amd64".to_string())
+        );
+        assert_eq!(parse_dpkg_search("bash: /etc/bash.bashrc\n", path), None);
+        assert_eq!(
+            parse_dpkg_verify(
+                "??5??????   /usr/lib/a.pem\n??5?????? c /etc/mysql/my.cnf\nmissing   c /etc/b\n"
+            ),
+            vec![PathBuf::from("/etc/mysql/my.cnf")]
+        );
+    }
+
+    #[test]
+    fn parse_pacman() {
+        assert_eq!(
+            parse_pacman_owner("/etc/pacman.conf is owned by pacman 6.0.1-1\n"),
+            Some(PackageOwner {
+                package: "pacman".to_string(),
+                version: "6.0.1-1".to_string(),
+            })
+        );
+        assert_eq!(parse_pacman_owner("error: No package owns /etc/a\n"), None);
+        let out = "Name            : pacman
+Backup Files    : /etc/makepkg.conf\tUNMODIFIED
+                  /etc/pacman.conf\tMODIFIED
+";
+        assert_eq!(
+            parse_pacman_modified(out),
+            vec![PathBuf::from("/etc/pacman.conf")]
+        );
+    }
+
+    #[test]
+    fn detect_package_manager() {
+        let all = |_: &str| true;
+        let fedora = "NAME=\"Fedora Linux\"\nID=fedora\n";
+        assert_eq!(detect_from(fedora, all), Some(PackageManager::Dnf));
+        let mint = "ID=linuxmint\nID_LIKE=\"ubuntu debian\"\n";
+        assert_eq!(detect_from(mint, all), Some(PackageManager::AptGet));
+        let tumbleweed = "ID=\"opensuse-tumbleweed\"\nID_LIKE=\"opensuse suse\"\n";
+        assert_eq!(detect_from(tumbleweed, all), Some(PackageManager::Zypper));
+        // 发行版对应的命令不存在时按PATH中的命令判断
+        assert_eq!(
+            detect_from(fedora, |command| command == "pacman"),
+            Some(PackageManager::Pacman)
+        );
+        assert_eq!(
+            detect_from("", |command| command == "apk"),
+            Some(PackageManager::Apk)
+        );
+        assert_eq!(detect_from(fedora, |_| false), None);
+    }
+
+    #[test]
+    fn parse_installed_versions() {
+        assert_eq!(
+            parse_dpkg_status("install ok installed\t5.9-4+b2"),
+            Some("5.9-4+b2".to_string())
+        );
+        assert_eq!(parse_dpkg_status("deinstall ok config-files\t5.9-4"), None);
+        assert_eq!(
+            parse_name_versions("black 23.1.0\nzsh 5.9-1\n", "zsh"),
+            Some("5.9-1".to_string())
+        );
+        assert_eq!(
+            parse_rpm_query("zsh\t5.9-5.fc38\nzsh\t5.9-5.fc38\n"),
+            Some(PackageOwner {
+                package: "zsh".to_string(),
+                version: "5.9-5.fc38".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_apk_owner("/etc/motd is owned by alpine-baselayout-3.4.3-r1\n"),
+            Some(PackageOwner {
+                package: "alpine-baselayout".to_string(),
+                version: "3.4.3-r1".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_apk_list("zsh-vcs-5.9-r2 x86_64 {zsh} (MIT) [installed]\nzsh-5.9-r2 x86_64 {zsh} (MIT) [installed]\n", "zsh"),
+            Some("5.9-r2".to_string())
+        );
+        let cargo = "cargo-edit v0.11.9:\n    cargo-add\nripgrep v13.0.0 (/src/ripgrep):\n    rg\n";
+        assert_eq!(
+            parse_cargo_list(cargo, "ripgrep"),
+            Some("13.0.0".to_string())
+        );
+        assert_eq!(parse_cargo_list(cargo, "cargo-add"), None);
+        let npm = "/usr/lib:\n/usr/lib/node_modules/@vue/cli:@vue/cli@5.0.8\n/usr/lib/node_modules/npm:npm@9.6.7\n";
+        assert_eq!(parse_npm_list(npm, "@vue/cli"), Some("5.0.8".to_string()));
+        assert_eq!(parse_npm_list(npm, "yarn"), None);
+    }
+
+    #[test]
+    fn resolve_package_names() {
+        #[derive(Deserialize)]
+        struct Definition {
+            packages: PackageNames,
+        }
+        let names = |content: &str| toml::from_str::<Definition>(content).unwrap().packages;
+
+        let rust =
+            names(r#"packages = { apt-get = ["libssl-dev", "pkg-config"], pacman = "openssl" }"#);
+        assert_eq!(
+            rust.get(&PackageManager::AptGet),
+            Some(vec!["libssl-dev".to_string(), "pkg-config".to_string()])
+        );
+        assert_eq!(
+            rust.get(&PackageManager::Pacman),
+            Some(vec!["openssl".to_string()])
+        );
+        assert_eq!(rust.get(&PackageManager::Dnf), None);
+        let zsh = names(r#"packages = "zsh""#);
+        assert_eq!(zsh.get(&PackageManager::Apk), Some(vec!["zsh".to_string()]));
+        let git = names(r#"packages = ["git", "git-lfs"]"#);
+        assert_eq!(git.get(&PackageManager::Dnf).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn save_and_load_manifest() {
+        let path = env::temp_dir().join("auto_configuration_packages.toml");
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            PackageManifest::load(&path).unwrap(),
+            PackageManifest::default()
+        );
+        let mut manifest = PackageManifest::default();
+        let owner = PackageOwner {
+            package: "mysql-common".to_string(),
+            version: "5.8+1.0.5".to_string(),
+        };
+        let non_utf8 = Path::new(OsStr::from_bytes(b"/etc/a%\xff.cnf"));
+        manifest.insert(Path::new("/etc/mysql/my.cnf"), owner.clone());
+        manifest.insert(non_utf8, owner.clone());
+        manifest.save(&path).unwrap();
+        let mut loaded = PackageManifest::load(&path).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(encode_path(non_utf8), "/etc/a%25%FF.cnf");
+        assert_eq!(loaded.remove(non_utf8), Some(owner));
+        assert_eq!(
+            decode_path("/etc/100%.conf"),
+            PathBuf::from("/etc/100%.conf")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
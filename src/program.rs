@@ -0,0 +1,649 @@
+use crate::configuration::{PackageManager, ShellConfiguration};
+use crate::hook::run_with_timeout;
+use crate::package::PackageNames;
+use crate::provision::PlanStep;
+use crate::settings::expand_env;
+use crate::Program;
+
+use regex::Regex;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// 没有配置env-file时导出环境变量的文件
+const DEFAULT_ENV_FILE: &str = "$HOME/.profile";
+
+/// 没有配置version-regex时从`--version`的输出中取出版本
+const DEFAULT_VERSION_REGEX: &str = r"(\d+(?:\.\d+)+)";
+
+/// `--version`没有在该时间内退出时被kill
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// configuration.toml中的`[program.<name>]`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProgramDefinition {
+    /// 不配置时使用`[program]`的package-manager或检测到的包管理器
+    pub package_manager: Option<PackageManager>,
+    /// 所有包管理器共用的包名，或按包管理器配置的包名，没有配置时使用程序名
+    pub packages: Option<PackageNames>,
+    /// exists检查的命令，默认为程序名
+    pub command: Option<String>,
+    /// 依赖的其它程序
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    /// 安装后从备份恢复的配置文件或目录，支持环境变量
+    #[serde(default)]
+    pub config_files: Vec<String>,
+    /// 每次config都通过`sh -c`执行的命令，不能重复执行的命令需要配置creates或unless
+    #[serde(default)]
+    pub post_install: Vec<PostInstall>,
+    /// 通过git clone安装的插件
+    #[serde(default)]
+    pub plugins: Vec<GitPlugin>,
+    /// 导出到env-file中的环境变量
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// 默认为`$HOME/.profile`
+    pub env_file: Option<String>,
+    /// command的版本低于min-version时视为不存在
+    pub min_version: Option<String>,
+    /// 从`command --version`的输出中取出版本，有分组时使用第一个分组
+    pub version_regex: Option<String>,
+}
+
+/// post-install中的一条命令
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum PostInstall {
+    Command(String),
+    Guarded(GuardedCommand),
+}
+
+/// creates存在或unless执行成功时跳过的命令
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuardedCommand {
+    pub command: String,
+    /// 命令创建的文件，支持环境变量
+    pub creates: Option<String>,
+    /// 通过`sh -c`执行的检查命令
+    pub unless: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitPlugin {
+    pub url: String,
+    /// clone到的目录，支持环境变量，已存在时跳过
+    pub path: String,
+}
+
+/// 由[ProgramDefinition]定义的程序，路径中的环境变量在创建时展开
+#[derive(Debug)]
+pub struct DeclaredProgram {
+    name: String,
+    pm: PackageManager,
+    packages: Vec<String>,
+    command: String,
+    dependencies: Vec<String>,
+    config_files: Vec<PathBuf>,
+    post_install: Vec<PostInstallCommand>,
+    plugins: Vec<(String, PathBuf)>,
+    env: BTreeMap<String, String>,
+    env_file: PathBuf,
+    version_requirement: Option<VersionRequirement>,
+}
+
+/// creates中的环境变量已展开的[GuardedCommand]
+#[derive(Debug)]
+struct PostInstallCommand {
+    command: String,
+    creates: Option<PathBuf>,
+    unless: Option<String>,
+}
+
+impl PostInstallCommand {
+    fn new(post_install: &PostInstall) -> io::Result<Self> {
+        Ok(match post_install {
+            PostInstall::Command(command) => PostInstallCommand {
+                command: command.clone(),
+                creates: None,
+                unless: None,
+            },
+            PostInstall::Guarded(guarded) => PostInstallCommand {
+                command: guarded.command.clone(),
+                creates: guarded
+                    .creates
+                    .as_deref()
+                    .map(|path| expand_env(path).map(PathBuf::from))
+                    .transpose()?,
+                unless: guarded.unless.clone(),
+            },
+        })
+    }
+
+    /// 是否可以跳过，unless无法执行时视为不能跳过
+    fn is_done(&self) -> bool {
+        if let Some(path) = &self.creates {
+            if path.exists() {
+                return true;
+            }
+        }
+        match &self.unless {
+            Some(unless) => run(Command::new("sh").arg("-c").arg(unless)).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// 程序的状态，见[Program::get_status]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgramStatus {
+    /// PATH中没有命令
+    Missing,
+    /// 命令的版本低于minimum，无法取得版本时version为None
+    Outdated {
+        path: PathBuf,
+        version: Option<String>,
+        minimum: String,
+    },
+    /// 命令存在，但包不是通过包管理器安装的，如手动编译或其它包管理器
+    Present(PathBuf),
+    /// 命令存在且包已通过包管理器安装
+    Installed(PathBuf),
+}
+
+impl fmt::Display for ProgramStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgramStatus::Missing => write!(f, "missing"),
+            ProgramStatus::Outdated {
+                path,
+                version,
+                minimum,
+            } => write!(
+                f,
+                "outdated {} ({} < {})",
+                path.display(),
+                version.as_deref().unwrap_or("unknown"),
+                minimum
+            ),
+            ProgramStatus::Present(path) => write!(f, "present {} (not managed)", path.display()),
+            ProgramStatus::Installed(path) => write!(f, "installed {}", path.display()),
+        }
+    }
+}
+
+/// 命令的最低版本
+#[derive(Debug, Clone)]
+pub struct VersionRequirement {
+    minimum: String,
+    regex: Regex,
+    timeout: Duration,
+}
+
+impl VersionRequirement {
+    /// regex不配置时使用[DEFAULT_VERSION_REGEX]，无效时返回ErrorKind::InvalidInput
+    pub fn new(minimum: &str, regex: Option<&str>) -> io::Result<Self> {
+        let regex = Regex::new(regex.unwrap_or(DEFAULT_VERSION_REGEX)).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("version-regex无效: {}", e),
+            )
+        })?;
+        Ok(VersionRequirement {
+            minimum: minimum.to_string(),
+            regex,
+            timeout: VERSION_TIMEOUT,
+        })
+    }
+
+    pub fn get_minimum(&self) -> &str {
+        &self.minimum
+    }
+
+    /// 执行`command --version`，在stdout与stderr的输出中匹配版本，
+    /// 超过[VERSION_TIMEOUT]时返回ErrorKind::TimedOut
+    pub fn get_version(&self, command: &Path) -> io::Result<Option<String>> {
+        let (status, output) =
+            run_with_timeout(Command::new(command).arg("--version"), self.timeout)?;
+        if status.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} --version 超时", command.display()),
+            ));
+        }
+        Ok(self.find_version(&String::from_utf8_lossy(&output)))
+    }
+
+    fn find_version(&self, output: &str) -> Option<String> {
+        let cap = self.regex.captures(output)?;
+        cap.get(1)
+            .or_else(|| cap.get(0))
+            .map(|m| m.as_str().to_string())
+    }
+
+    pub fn accepts(&self, version: &str) -> bool {
+        compare_versions(version, &self.minimum) != Ordering::Less
+    }
+}
+
+/// 按数字逐段比较，缺少的段视为0，非数字字符只作为分隔
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parse = |s: &str| {
+        s.split(|c: char| !c.is_ascii_digit())
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<u64>().unwrap_or(u64::MAX))
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (parse(a), parse(b));
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0)))
+        .find(|ord| *ord != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// 在PATH中查找可执行文件name，name包含`/`时只检查该路径
+pub fn find_command(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) {
+            Some(path)
+        } else {
+            None
+        };
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// 是否为shell中合法的变量名：`^[A-Za-z_][A-Za-z0-9_]*$`
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl DeclaredProgram {
+    /// env中的变量名不合法时返回ErrorKind::InvalidInput
+    pub fn new(name: &str, definition: &ProgramDefinition, pm: PackageManager) -> io::Result<Self> {
+        let expand = |path: &str| expand_env(path).map(PathBuf::from);
+        // 变量名会被用于匹配env-file中的内容
+        if let Some(key) = definition.env.keys().find(|key| !is_env_name(key)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("program: {} 的env中有非法的变量名: {}", name, key),
+            ));
+        }
+        Ok(DeclaredProgram {
+            name: name.to_string(),
+            pm,
+            packages: definition
+                .packages
+                .as_ref()
+                .and_then(|packages| packages.get(&pm))
+                .unwrap_or_else(|| vec![name.to_string()]),
+            command: definition
+                .command
+                .clone()
+                .unwrap_or_else(|| name.to_string()),
+            dependencies: definition.dependencies.clone(),
+            config_files: definition
+                .config_files
+                .iter()
+                .map(|path| expand(path))
+                .collect::<io::Result<_>>()?,
+            post_install: definition
+                .post_install
+                .iter()
+                .map(PostInstallCommand::new)
+                .collect::<io::Result<_>>()?,
+            plugins: definition
+                .plugins
+                .iter()
+                .map(|plugin| Ok((plugin.url.clone(), expand(&plugin.path)?)))
+                .collect::<io::Result<_>>()?,
+            env: definition.env.clone(),
+            env_file: expand(definition.env_file.as_deref().unwrap_or(DEFAULT_ENV_FILE))?,
+            version_requirement: definition
+                .min_version
+                .as_deref()
+                .map(|minimum| {
+                    VersionRequirement::new(minimum, definition.version_regex.as_deref())
+                })
+                .transpose()?,
+        })
+    }
+
+    /// 导出env后env_file的内容，没有变化时返回None
+    fn get_exported_env_file(&self) -> io::Result<Option<String>> {
+        if self.env.is_empty() {
+            return Ok(None);
+        }
+        let content = match fs::read_to_string(&self.env_file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut shell = ShellConfiguration::new(&content);
+        for (name, value) in &self.env {
+            if shell.is_exported_var(name) && shell.get_var(name).as_ref() == Some(value) {
+                continue;
+            }
+            shell.remove_var(name);
+            shell.put_var(name, value);
+            shell.export_var(name);
+        }
+        if shell.get_content() == content {
+            Ok(None)
+        } else {
+            Ok(Some(shell.get_content().to_string()))
+        }
+    }
+}
+
+impl Program for DeclaredProgram {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_package_manager(&self) -> &PackageManager {
+        &self.pm
+    }
+
+    fn get_package_names(&self) -> Vec<String> {
+        self.packages.clone()
+    }
+
+    fn get_command(&self) -> &str {
+        &self.command
+    }
+
+    fn get_version_requirement(&self) -> Option<&VersionRequirement> {
+        self.version_requirement.as_ref()
+    }
+
+    /// clone插件、执行post-install并导出环境变量，已恢复的配置文件见[crate::provision]
+    fn config(&self) -> io::Result<()> {
+        for (url, path) in &self.plugins {
+            if !path.exists() {
+                run(Command::new("git").arg("clone").arg(url).arg(path))?;
+            }
+        }
+        for post_install in self.post_install.iter().filter(|c| !c.is_done()) {
+            run(Command::new("sh").arg("-c").arg(&post_install.command))?;
+        }
+        if let Some(content) = self.get_exported_env_file()? {
+            fs::write(&self.env_file, content)?;
+        }
+        Ok(())
+    }
+
+    fn get_configuration_paths(&self) -> Vec<PathBuf> {
+        self.config_files.clone()
+    }
+
+    fn get_dependencies(&self) -> Vec<String> {
+        self.dependencies.clone()
+    }
+
+    fn plan_config(&self) -> Vec<PlanStep> {
+        let mut steps = vec![];
+        for (url, path) in &self.plugins {
+            if !path.exists() {
+                steps.push(PlanStep::GitClone {
+                    program: self.name.clone(),
+                    url: url.clone(),
+                    path: path.clone(),
+                });
+            }
+        }
+        for post_install in self.post_install.iter().filter(|c| !c.is_done()) {
+            steps.push(PlanStep::RunCommand {
+                program: self.name.clone(),
+                command: post_install.command.clone(),
+            });
+        }
+        match self.get_exported_env_file() {
+            Ok(Some(_)) => steps.push(PlanStep::WriteFile {
+                program: self.name.clone(),
+                path: self.env_file.clone(),
+                detail: format!(
+                    "export {}",
+                    self.env.keys().cloned().collect::<Vec<_>>().join(" ")
+                ),
+            }),
+            Ok(None) => {}
+            Err(e) => eprintln!("path: {} 读取失败: {}", self.env_file.display(), e),
+        }
+        steps
+    }
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let out = command.stdin(Stdio::null()).output()?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} 失败: code={:?}, {}",
+            command,
+            out.status.code(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::tests::{commit_file, init_repo};
+    use std::env;
+
+    #[test]
+    fn config_declared_program() {
+        let dir = env::temp_dir().join("auto_configuration_declared_program");
+        let _ = fs::remove_dir_all(&dir);
+        let plugin_repo = dir.join("plugin-repo");
+        init_repo(&plugin_repo);
+        commit_file(&plugin_repo, "plugin.zsh", "echo plugin\n", "init");
+        let env_file = dir.join("profile");
+        fs::write(&env_file, "ZSH=/old\n").unwrap();
+        let marker = dir.join("marker");
+        let log = dir.join("log");
+
+        let definition = ProgramDefinition {
+            command: Some("sh".to_string()),
+            config_files: vec![dir.join("zshrc").display().to_string()],
+            post_install: vec![
+                PostInstall::Guarded(GuardedCommand {
+                    command: format!("echo done >> {}", marker.display()),
+                    creates: Some(marker.display().to_string()),
+                    ..Default::default()
+                }),
+                PostInstall::Guarded(GuardedCommand {
+                    command: format!("echo again >> {}", log.display()),
+                    unless: Some(format!("grep -q again {}", log.display())),
+                    ..Default::default()
+                }),
+            ],
+            plugins: vec![GitPlugin {
+                url: plugin_repo.display().to_string(),
+                path: dir.join("plugins/a").display().to_string(),
+            }],
+            env: vec![
+                ("ZSH".to_string(), "/opt/zsh".to_string()),
+                ("EDITOR".to_string(), "vim".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            env_file: Some(env_file.display().to_string()),
+            ..Default::default()
+        };
+        let program = DeclaredProgram::new("zsh", &definition, PackageManager::Pacman).unwrap();
+        assert_eq!(program.get_command(), "sh");
+        assert!(program.exists());
+        assert_eq!(program.get_configuration_paths(), vec![dir.join("zshrc")]);
+
+        let steps = program.plan_config();
+        assert_eq!(steps.len(), 4);
+        assert!(
+            matches!(&steps[0], PlanStep::GitClone { path, .. } if *path == dir.join("plugins/a"))
+        );
+        assert!(matches!(&steps[1], PlanStep::RunCommand { .. }));
+        assert!(matches!(&steps[2], PlanStep::RunCommand { .. }));
+        assert!(matches!(&steps[3], PlanStep::WriteFile { path, .. } if *path == env_file));
+        assert!(!marker.exists());
+
+        program.config().unwrap();
+        assert!(dir.join("plugins/a/plugin.zsh").exists());
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "done\n");
+        let shell = ShellConfiguration::new(&fs::read_to_string(&env_file).unwrap());
+        assert!(shell.is_exported_var("ZSH"));
+        assert!(shell.is_exported_var("EDITOR"));
+        assert_eq!(shell.get_var("ZSH"), Some("/opt/zsh".to_string()));
+
+        assert_eq!(fs::read_to_string(&log).unwrap(), "again\n");
+
+        // 插件、环境变量已存在，post-install的creates与unless满足时不再重复
+        program.config().unwrap();
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "done\n");
+        assert_eq!(fs::read_to_string(&log).unwrap(), "again\n");
+        assert!(program.plan_config().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_env_name() {
+        for key in ["A(", "1A", "A.B", ""] {
+            let definition = ProgramDefinition {
+                env: vec![(key.to_string(), "1".to_string())]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            };
+            let err = DeclaredProgram::new("a", &definition, PackageManager::Pacman).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", key);
+        }
+        let definition = ProgramDefinition {
+            env: vec![("_A1".to_string(), "1".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert!(DeclaredProgram::new("a", &definition, PackageManager::Pacman).is_ok());
+    }
+
+    #[test]
+    fn find_command_and_version() {
+        let dir = env::temp_dir().join("auto_configuration_find_command");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("tool");
+        fs::write(&tool, "#!/bin/sh\necho 'tool version 5.8.1 (x86_64)' >&2\n").unwrap();
+        let not_executable = dir.join("not-executable");
+        fs::write(&not_executable, "").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(
+            find_command(&tool.display().to_string()),
+            Some(tool.clone())
+        );
+        assert_eq!(find_command(&not_executable.display().to_string()), None);
+        assert!(find_command("sh").is_some());
+        assert_eq!(find_command("_none_command_test"), None);
+
+        let requirement = VersionRequirement::new("5.8", None).unwrap();
+        assert_eq!(
+            requirement.get_version(&tool).unwrap(),
+            Some("5.8.1".to_string())
+        );
+        assert!(requirement.accepts("5.8.1"));
+        assert!(requirement.accepts("5.10"));
+        assert!(!requirement.accepts("5.7.9"));
+        let requirement = VersionRequirement::new("5.9", Some(r"version (\S+)")).unwrap();
+        assert_eq!(
+            requirement.get_version(&tool).unwrap(),
+            Some("5.8.1".to_string())
+        );
+        assert!(VersionRequirement::new("1", Some("(")).is_err());
+        let slow = dir.join("slow");
+        fs::write(&slow, "#!/bin/sh\nexec sleep 10\n").unwrap();
+        fs::set_permissions(&slow, fs::Permissions::from_mode(0o755)).unwrap();
+        let requirement = VersionRequirement {
+            timeout: Duration::from_millis(100),
+            ..VersionRequirement::new("1", None).unwrap()
+        };
+        assert_eq!(
+            requirement.get_version(&slow).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        let definition = ProgramDefinition {
+            command: Some(tool.display().to_string()),
+            min_version: Some("5.9".to_string()),
+            ..Default::default()
+        };
+        let program = DeclaredProgram::new("tool", &definition, PackageManager::Other).unwrap();
+        assert!(!program.exists());
+        assert_eq!(
+            program.get_status().unwrap(),
+            ProgramStatus::Outdated {
+                path: tool.clone(),
+                version: Some("5.8.1".to_string()),
+                minimum: "5.9".to_string()
+            }
+        );
+        let definition = ProgramDefinition {
+            min_version: Some("5.8".to_string()),
+            ..definition
+        };
+        let program = DeclaredProgram::new("tool", &definition, PackageManager::Other).unwrap();
+        assert!(program.exists());
+        // Other无法查询是否通过包管理器安装
+        assert_eq!(program.get_status().unwrap(), ProgramStatus::Present(tool));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_program() {
+        let program = DeclaredProgram::new(
+            "_none_command_test",
+            &ProgramDefinition::default(),
+            PackageManager::Other,
+        )
+        .unwrap();
+        assert!(!program.exists());
+        assert_eq!(program.get_status().unwrap(), ProgramStatus::Missing);
+    }
+
+    #[test]
+    fn default_declared_program() {
+        let program = DeclaredProgram::new(
+            "ripgrep",
+            &ProgramDefinition::default(),
+            PackageManager::Cargo,
+        )
+        .unwrap();
+        assert_eq!(program.get_command(), "ripgrep");
+        assert_eq!(program.get_package_names(), vec!["ripgrep"]);
+        assert!(program.get_dependencies().is_empty());
+        assert!(program.plan_config().is_empty());
+    }
+}
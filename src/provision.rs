@@ -0,0 +1,492 @@
+use crate::configuration::{PackageManager, PrivilegeStrategy};
+use crate::restore::{RestoreChange, RestorePoint};
+use crate::{BackupContext, Program};
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// 执行安装计划时的一个操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanStep {
+    /// 一次install_multiple
+    InstallPackages {
+        manager: PackageManager,
+        packages: Vec<String>,
+        /// 将执行的命令
+        command: String,
+    },
+    WriteFile {
+        program: String,
+        path: PathBuf,
+        /// 如`restore from <revision>`
+        detail: String,
+    },
+    GitClone {
+        program: String,
+        url: String,
+        path: PathBuf,
+    },
+    RunCommand {
+        program: String,
+        command: String,
+    },
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanStep::InstallPackages {
+                manager,
+                packages,
+                command,
+            } => write!(
+                f,
+                "install ({}): {}\n    $ {}",
+                manager.get_name(),
+                packages.join(" "),
+                command
+            ),
+            PlanStep::WriteFile {
+                program,
+                path,
+                detail,
+            } => write!(f, "[{}] write {} ({})", program, path.display(), detail),
+            PlanStep::GitClone { program, url, path } => {
+                write!(f, "[{}] git clone {} {}", program, url, path.display())
+            }
+            PlanStep::RunCommand { program, command } => write!(f, "[{}] $ {}", program, command),
+        }
+    }
+}
+
+impl PlanStep {
+    /// 一行JSON对象，`action`为操作的类型
+    pub fn to_json(&self) -> String {
+        let fields = match self {
+            PlanStep::InstallPackages {
+                manager,
+                packages,
+                command,
+            } => vec![
+                ("action", json_string("install-packages")),
+                ("manager", json_string(manager.get_name())),
+                (
+                    "packages",
+                    format!(
+                        "[{}]",
+                        packages
+                            .iter()
+                            .map(|package| json_string(package))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    ),
+                ),
+                ("command", json_string(command)),
+            ],
+            PlanStep::WriteFile {
+                program,
+                path,
+                detail,
+            } => vec![
+                ("action", json_string("write-file")),
+                ("program", json_string(program)),
+                ("path", json_string(&path.display().to_string())),
+                ("detail", json_string(detail)),
+            ],
+            PlanStep::GitClone { program, url, path } => vec![
+                ("action", json_string("git-clone")),
+                ("program", json_string(program)),
+                ("url", json_string(url)),
+                ("path", json_string(&path.display().to_string())),
+            ],
+            PlanStep::RunCommand { program, command } => vec![
+                ("action", json_string("run-command")),
+                ("program", json_string(program)),
+                ("command", json_string(command)),
+            ],
+        };
+        let fields = fields
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json_string(key), value))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// 逐行列出steps，最后为统计
+pub fn render_report(steps: &[PlanStep]) -> String {
+    let (mut packages, mut files, mut clones, mut commands) = (0, 0, 0, 0);
+    let mut report = String::new();
+    for step in steps {
+        match step {
+            PlanStep::InstallPackages {
+                packages: names, ..
+            } => packages += names.len(),
+            PlanStep::WriteFile { .. } => files += 1,
+            PlanStep::GitClone { .. } => clones += 1,
+            PlanStep::RunCommand { .. } => commands += 1,
+        }
+        report.push_str(&format!("{}\n", step));
+    }
+    report.push_str(&format!(
+        "{} packages to install, {} files to write, {} repositories to clone, {} commands to run\n",
+        packages, files, clones, commands
+    ));
+    report
+}
+
+/// steps的JSON数组
+pub fn to_json(steps: &[PlanStep]) -> String {
+    let steps = steps.iter().map(PlanStep::to_json).collect::<Vec<_>>();
+    format!("[{}]", steps.join(",\n"))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// 批量安装程序的计划
+pub struct InstallPlan<'a> {
+    /// 按依赖排序的程序，依赖在前
+    pub programs: Vec<&'a dyn Program>,
+    /// 每个包管理器需要安装的包，按程序的顺序排列
+    pub packages: Vec<(PackageManager, Vec<String>)>,
+}
+
+/// 从programs中找出names及其依赖的程序，按依赖排序并按包管理器合并未安装的包，
+/// 没有包名的程序(如通过git clone安装的插件)只执行config
+///
+/// 依赖不存在时返回ErrorKind::NotFound，循环依赖时返回ErrorKind::InvalidInput
+pub fn plan<'a>(programs: &'a [Box<dyn Program>], names: &[String]) -> io::Result<InstallPlan<'a>> {
+    let mut ordered = vec![];
+    let mut visiting = vec![];
+    for name in names {
+        visit(programs, name, &mut visiting, &mut ordered)?;
+    }
+
+    let mut packages: Vec<(PackageManager, Vec<String>)> = vec![];
+    for program in ordered.iter().filter(|program| !program.exists()) {
+        let names = program.get_package_names();
+        if names.is_empty() {
+            continue;
+        }
+        let pm = *program.get_package_manager();
+        let index = match packages.iter().position(|(manager, _)| *manager == pm) {
+            Some(index) => index,
+            None => {
+                packages.push((pm, vec![]));
+                packages.len() - 1
+            }
+        };
+        for package in names {
+            if !packages[index].1.contains(&package) {
+                packages[index].1.push(package);
+            }
+        }
+    }
+    Ok(InstallPlan {
+        programs: ordered,
+        packages,
+    })
+}
+
+/// 深度优先，依赖在程序之前加入ordered；visiting为当前路径，用于发现循环
+fn visit<'a>(
+    programs: &'a [Box<dyn Program>],
+    name: &str,
+    visiting: &mut Vec<String>,
+    ordered: &mut Vec<&'a dyn Program>,
+) -> io::Result<()> {
+    if ordered.iter().any(|program| program.get_name() == name) {
+        return Ok(());
+    }
+    if visiting.iter().any(|visited| visited == name) {
+        visiting.push(name.to_string());
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("循环依赖: {}", visiting.join(" -> ")),
+        ));
+    }
+    let program = programs
+        .iter()
+        .find(|program| program.get_name() == name)
+        .ok_or_else(|| {
+            let detail = match visiting.last() {
+                Some(parent) => format!("{} 依赖的程序: {} 不存在", parent, name),
+                None => format!("程序: {} 不存在", name),
+            };
+            io::Error::new(io::ErrorKind::NotFound, detail)
+        })?;
+    visiting.push(name.to_string());
+    for dependency in program.get_dependencies() {
+        visit(programs, &dependency, visiting, ordered)?;
+    }
+    visiting.pop();
+    ordered.push(program.as_ref());
+    Ok(())
+}
+
+impl InstallPlan<'_> {
+    /// 将执行的所有操作：先安装包，再按依赖顺序恢复每个程序的配置文件并执行config
+    pub fn to_steps(
+        &self,
+        context: &BackupContext,
+        privilege: PrivilegeStrategy,
+    ) -> io::Result<Vec<PlanStep>> {
+        let mut steps = vec![];
+        for (manager, packages) in &self.packages {
+            steps.push(PlanStep::InstallPackages {
+                manager: *manager,
+                command: manager.describe_install(packages, privilege)?,
+                packages: packages.clone(),
+            });
+        }
+        for program in &self.programs {
+            for change in context.restore_program_files(*program, true)? {
+                let detail = match change.diff {
+                    Some(_) => format!("restore from {}", change.revision),
+                    None => format!("restore from {}, new file", change.revision),
+                };
+                steps.push(PlanStep::WriteFile {
+                    program: program.get_name().to_string(),
+                    path: change.path,
+                    detail,
+                });
+            }
+            steps.extend(program.plan_config());
+        }
+        Ok(steps)
+    }
+
+    /// 每个包管理器执行一次install_multiple，之后按依赖顺序恢复配置文件并执行config
+    pub fn execute(&self, context: &BackupContext, privilege: PrivilegeStrategy) -> io::Result<()> {
+        for (pm, names) in &self.packages {
+            pm.install_multiple(names.iter().map(String::as_str).collect(), privilege)?;
+        }
+        for program in &self.programs {
+            context.restore_program_files(*program, false)?;
+            program.config().map_err(|e| {
+                io::Error::new(e.kind(), format!("{} 配置失败: {}", program.get_name(), e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl BackupContext {
+    /// 将program的配置文件恢复为最新的备份，没有备份的路径被跳过
+    pub fn restore_program_files(
+        &self,
+        program: &dyn Program,
+        dry_run: bool,
+    ) -> io::Result<Vec<RestoreChange>> {
+        // 还没有任何提交
+        if self.storage.resolve("HEAD").is_err() {
+            return Ok(vec![]);
+        }
+        let point = RestorePoint::Revision("HEAD".to_string());
+        let mut changes = vec![];
+        for path in program.get_configuration_paths() {
+            match self.restore_at(&[path], &point, dry_run) {
+                Ok(restored) => changes.extend(restored),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    struct TestProgram {
+        name: String,
+        pm: PackageManager,
+        installed: bool,
+        packages: Vec<String>,
+        dependencies: Vec<String>,
+        paths: Vec<PathBuf>,
+        configured: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Program for TestProgram {
+        fn get_name(&self) -> &str {
+            &self.name
+        }
+
+        fn get_package_manager(&self) -> &PackageManager {
+            &self.pm
+        }
+
+        fn get_package_names(&self) -> Vec<String> {
+            self.packages.clone()
+        }
+
+        fn config(&self) -> io::Result<()> {
+            self.configured.lock().unwrap().push(self.name.clone());
+            Ok(())
+        }
+
+        fn get_dependencies(&self) -> Vec<String> {
+            self.dependencies.clone()
+        }
+
+        fn exists(&self) -> bool {
+            self.installed
+        }
+
+        fn get_configuration_paths(&self) -> Vec<PathBuf> {
+            self.paths.clone()
+        }
+
+        fn plan_config(&self) -> Vec<PlanStep> {
+            vec![PlanStep::RunCommand {
+                program: self.name.clone(),
+                command: format!("echo \"{}\"", self.name),
+            }]
+        }
+    }
+
+    fn programs(configured: &Arc<Mutex<Vec<String>>>) -> Vec<Box<dyn Program>> {
+        let program = |name: &str, pm, installed, dependencies: &[&str]| -> Box<dyn Program> {
+            Box::new(TestProgram {
+                name: name.to_string(),
+                pm,
+                installed,
+                // Other中的程序没有包，只执行config
+                packages: match pm {
+                    PackageManager::Other => vec![],
+                    _ => vec![name.to_string()],
+                },
+                dependencies: dependencies.iter().map(|s| s.to_string()).collect(),
+                paths: vec![],
+                configured: Arc::clone(configured),
+            })
+        };
+        vec![
+            program("oh-my-zsh", PackageManager::Other, false, &["zsh", "git"]),
+            program("zsh", PackageManager::AptGet, false, &[]),
+            program("git", PackageManager::AptGet, false, &[]),
+            program("ripgrep", PackageManager::Cargo, false, &["git"]),
+            program("a", PackageManager::Other, true, &["b"]),
+            program("b", PackageManager::Other, true, &["a"]),
+            program("c", PackageManager::Other, true, &["none"]),
+        ]
+    }
+
+    #[test]
+    fn plan_with_dependencies() {
+        let configured = Arc::new(Mutex::new(vec![]));
+        let programs = programs(&configured);
+        let names = ["ripgrep", "oh-my-zsh"].map(String::from);
+        let install_plan = plan(&programs, &names).unwrap();
+        let order = install_plan
+            .programs
+            .iter()
+            .map(|program| program.get_name())
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec!["git", "ripgrep", "zsh", "oh-my-zsh"]);
+        // 没有安装的oh-my-zsh没有包名，不会产生空的安装
+        assert_eq!(
+            install_plan.packages,
+            vec![
+                (
+                    PackageManager::AptGet,
+                    vec!["git".to_string(), "zsh".to_string()]
+                ),
+                (PackageManager::Cargo, vec!["ripgrep".to_string()]),
+            ]
+        );
+
+        let err = plan(&programs, &["a".to_string()]).map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("a -> b -> a"));
+        let err = plan(&programs, &["c".to_string()]).map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    fn backup_context(dir: &std::path::Path) -> BackupContext {
+        BackupContext::for_test(vec![], &dir.join("backup"))
+    }
+
+    #[test]
+    fn execute_config_in_order() {
+        let dir = env::temp_dir().join("auto_configuration_provision");
+        let context = backup_context(&dir);
+        let configured = Arc::new(Mutex::new(vec![]));
+        let programs = programs(&configured);
+        let mut install_plan = plan(&programs, &["oh-my-zsh".to_string()]).unwrap();
+        // 不执行真正的安装
+        install_plan.packages.clear();
+        install_plan
+            .execute(&context, PrivilegeStrategy::Refuse)
+            .unwrap();
+        assert_eq!(*configured.lock().unwrap(), vec!["zsh", "git", "oh-my-zsh"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_steps() {
+        let dir = env::temp_dir().join("auto_configuration_provision_plan");
+        let context = backup_context(&dir);
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let a = src.join("a.conf");
+        fs::write(&a, "backup").unwrap();
+        context.hold(&a).unwrap();
+        context.commit(&a).unwrap();
+        fs::write(&a, "changed").unwrap();
+
+        let configured = Arc::new(Mutex::new(vec![]));
+        let programs: Vec<Box<dyn Program>> = vec![Box::new(TestProgram {
+            name: "a".to_string(),
+            pm: PackageManager::Pacman,
+            installed: false,
+            packages: vec!["a".to_string()],
+            dependencies: vec![],
+            paths: vec![a.clone(), src.join("none")],
+            configured: Arc::clone(&configured),
+        })];
+        let install_plan = plan(&programs, &["a".to_string()]).unwrap();
+        let steps = install_plan
+            .to_steps(&context, PrivilegeStrategy::Refuse)
+            .unwrap();
+        assert_eq!(steps.len(), 3);
+        // Refuse时不提权，也不因为没有root权限而失败
+        assert_eq!(
+            steps[0].to_string(),
+            "install (pacman): a\n    $ pacman -S --needed --noconfirm a"
+        );
+        assert!(matches!(&steps[1], PlanStep::WriteFile { path, .. } if *path == a));
+        assert_eq!(steps[2].to_string(), "[a] $ echo \"a\"");
+        assert_eq!(
+            steps[2].to_json(),
+            r#"{"action":"run-command","program":"a","command":"echo \"a\""}"#
+        );
+        assert!(render_report(&steps).ends_with(
+            "1 packages to install, 1 files to write, 0 repositories to clone, 1 commands to run\n"
+        ));
+        // 没有执行任何操作
+        assert_eq!(fs::read_to_string(&a).unwrap(), "changed");
+        assert!(configured.lock().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+use crate::storage;
+use crate::BackupContext;
+
+use notify::RecursiveMode;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 启动时提交的message
+pub const OFFLINE_CHANGES_MESSAGE: &str = "offline changes";
+
+impl BackupContext {
+    /// 比较所有from_paths下的文件与备份，hold存在差异的文件与源文件已被删除的备份并提交一次，
+    /// 返回被hold的文件
+    ///
+    /// 源文件的mtime不晚于备份且大小相同时认为没有修改，否则再比较hash。
+    /// 用于在开始监听前补上程序未运行时的修改，无法比较的from_path被跳过
+    pub fn commit_offline_changes(&self) -> io::Result<Vec<PathBuf>> {
+        let mut held = vec![];
+        for config in self.configurations.iter() {
+            for (from_path, mode) in &config.from_paths {
+                if let Err(e) = self.hold_offline_changes(from_path, *mode, &mut held) {
+                    eprintln!("path: {} 比较备份失败: {}", from_path.display(), e);
+                }
+            }
+        }
+        if held.is_empty() {
+            return Ok(held);
+        }
+        self.commit_paths(OFFLINE_CHANGES_MESSAGE, &held)?;
+        Ok(held)
+    }
+
+    fn hold_offline_changes(
+        &self,
+        from_path: &Path,
+        mode: RecursiveMode,
+        held: &mut Vec<PathBuf>,
+    ) -> io::Result<()> {
+        let files = self.list_sources(from_path, mode)?;
+        for path in &files {
+            match self.is_changed(path) {
+                Ok(false) => continue,
+                Ok(true) => {}
+                Err(e) => {
+                    eprintln!("path: {} 比较备份失败: {}", path.display(), e);
+                    continue;
+                }
+            }
+            let res = self
+                .run_pre_commit_hooks(path)
+                .and_then(|_| self.hold(path));
+            match res {
+                Ok(_) => held.push(path.clone()),
+                Err(e) => eprintln!("config hold error: {}", e),
+            }
+        }
+        for path in self.list_backup_sources(from_path, mode)? {
+            if files.contains(&path) || !crate::is_removed(&path) {
+                continue;
+            }
+            match self.hold(&path) {
+                Ok(_) => held.push(path),
+                Err(e) => eprintln!("config hold error: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// from_path下的备份文件对应的源文件路径
+    fn list_backup_sources(
+        &self,
+        from_path: &Path,
+        mode: RecursiveMode,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mapper = self.get_mapper(from_path);
+        let backup_path =
+            mapper.to_backup_path(from_path, self.get_configuration_name(from_path))?;
+        let mut backups = vec![];
+        if backup_path.is_dir() && mode == RecursiveMode::Recursive {
+            crate::list_stored_files(&backup_path, &mut backups)?;
+        } else {
+            list_source_files(&backup_path, mode, &mut backups)?;
+        }
+        let manifest = self.get_packages_path();
+        backups
+            .iter()
+            .filter(|path| **path != manifest)
+            .map(|path| mapper.to_origin_path(path))
+            .collect()
+    }
+
+    fn is_changed(&self, from_path: &Path) -> io::Result<bool> {
+        let backup_path = self
+            .get_mapper(from_path)
+            .to_backup_path(from_path, self.get_configuration_name(from_path))?;
+        let backup = match fs::metadata(&backup_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        // 没有权限时直接比较通过helper读取的内容
+        match fs::metadata(from_path) {
+            Ok(source)
+                if source.len() == backup.len() && source.modified()? <= backup.modified()? =>
+            {
+                return Ok(false)
+            }
+            Err(e) if e.kind() != io::ErrorKind::PermissionDenied => return Err(e),
+            _ => {}
+        }
+        Ok(storage::hash_content(&self.read_source(from_path)?)
+            != storage::hash_content(&fs::read(&backup_path)?))
+    }
+}
+
+/// 获取path下的所有文件，path为文件时只包含path，不存在时为空
+pub(crate) fn list_source_files(
+    path: &Path,
+    mode: RecursiveMode,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    if !path.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        } else if path.is_dir() && mode == RecursiveMode::Recursive {
+            list_source_files(&path, mode, files)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history;
+    use crate::hook::{Hook, HookFailurePolicy, Hooks};
+    use crate::Configuration;
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn commit_changes_before_watching() {
+        let dir = env::temp_dir().join("auto_configuration_reconcile");
+        let backup = dir.join("backup");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let marker = dir.join("reloaded");
+        let config = Configuration {
+            hooks: Hooks {
+                post_commit: vec![Hook {
+                    command: format!("echo $AUTO_CONFIGURATION_PATH >> {}", marker.display()),
+                    timeout: 1,
+                    on_failure: HookFailurePolicy::Ignore,
+                }],
+                ..Hooks::default()
+            },
+            ..Configuration::for_test("test", &[&src])
+        };
+        let context = BackupContext::for_test(vec![config], &backup);
+        for name in &["a.txt", "b.txt", "sub/c.txt"] {
+            let path = src.join(name);
+            fs::write(&path, "1").unwrap();
+            context.hold(&path).unwrap();
+        }
+        context.commit_message("first").unwrap();
+        fs::remove_file(&marker).unwrap();
+
+        // 未运行时的修改：a修改了内容，b只修改了mtime，c被删除，d是新文件
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(src.join("a.txt"), "2").unwrap();
+        fs::write(src.join("b.txt"), "1").unwrap();
+        fs::remove_file(src.join("sub/c.txt")).unwrap();
+        fs::write(src.join("sub/d.txt"), "1").unwrap();
+        let mut held = context.commit_offline_changes().unwrap();
+        held.sort();
+        assert_eq!(
+            held,
+            vec![
+                src.join("a.txt"),
+                src.join("sub/c.txt"),
+                src.join("sub/d.txt")
+            ]
+        );
+        assert!(!context
+            .get_backup_file_path(&src.join("sub/c.txt"))
+            .unwrap()
+            .exists());
+        let files = history::git(
+            &backup,
+            ["show", "--name-status", "--no-renames", "--format="],
+        )
+        .unwrap();
+        assert!(String::from_utf8_lossy(&files).contains("D\t"));
+        // 启动时的提交也执行post-commit hooks
+        let mut reloaded = fs::read_to_string(&marker)
+            .unwrap()
+            .lines()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        reloaded.sort();
+        assert_eq!(reloaded, held);
+        let log = history::git(&backup, ["log", "-1", "--format=%s"]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&log).trim(),
+            OFFLINE_CHANGES_MESSAGE
+        );
+
+        assert!(context.commit_offline_changes().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use crate::mapping::BackupPathMapper;
+use crate::storage;
+use crate::{BackupContext, Configuration};
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// 要恢复到的历史位置
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestorePoint {
+    /// 存储中的revision，如commit id、`HEAD~2`
+    Revision(String),
+    /// git支持的日期格式，如`yesterday 18:00`，恢复到该时间之前的最后一个提交
+    Time(String),
+}
+
+/// 恢复一个文件时将发生的修改
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestoreChange {
+    /// 要被覆盖的源文件路径
+    pub path: PathBuf,
+    pub revision: String,
+    /// 从当前文件到revision时备份的差异，即恢复后`+`的行被写入，当前文件不存在时为None
+    pub diff: Option<String>,
+}
+
+/// 要写入源文件的内容
+#[derive(Debug)]
+pub(crate) struct RestoreContent {
+    pub path: PathBuf,
+    pub content: Vec<u8>,
+    /// 文件权限，None时不修改
+    pub mode: Option<u32>,
+}
+
+impl BackupContext {
+    pub fn resolve_restore_point(&self, point: &RestorePoint) -> io::Result<String> {
+        match point {
+            RestorePoint::Revision(revision) => self.storage.resolve(revision),
+            RestorePoint::Time(time) => self.storage.revision_before(time),
+        }
+    }
+
+    /// 将from_paths恢复到point时的备份
+    ///
+    /// dry_run时只返回将发生的修改。否则先将当前文件保存并提交一次，
+    /// 再覆盖为point时的内容，未修改的文件不会被写入
+    pub fn restore_at(
+        &self,
+        from_paths: &[PathBuf],
+        point: &RestorePoint,
+        dry_run: bool,
+    ) -> io::Result<Vec<RestoreChange>> {
+        let revision = self.resolve_restore_point(point)?;
+        let mut files = vec![];
+        for from_path in from_paths {
+            let rel_path = self.get_backup_relative_path(from_path)?;
+            if self
+                .storage
+                .list_files(&revision, std::slice::from_ref(&rel_path))?
+                .is_empty()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("path: {} 在{}时没有备份", from_path.display(), revision),
+                ));
+            }
+            files.push((from_path.clone(), rel_path));
+        }
+        self.restore_files(files, &revision, dry_run)
+    }
+
+    /// 将名为name的configuration中的所有文件恢复到point时的备份
+    pub fn restore_configuration_at(
+        &self,
+        name: &str,
+        point: &RestorePoint,
+        dry_run: bool,
+    ) -> io::Result<Vec<RestoreChange>> {
+        let config = self
+            .configurations
+            .iter()
+            .find(|config| config.name == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("configuration: {} 不存在", name),
+                )
+            })?;
+        let revision = self.resolve_restore_point(point)?;
+        let files = self.list_configuration_files(config, &revision)?;
+        self.restore_files(files, &revision, dry_run)
+    }
+
+    fn restore_files(
+        &self,
+        files: Vec<(PathBuf, PathBuf)>,
+        revision: &str,
+        dry_run: bool,
+    ) -> io::Result<Vec<RestoreChange>> {
+        let contents = files
+            .into_iter()
+            .map(|(path, rel_path)| {
+                Ok(RestoreContent {
+                    content: self.storage.show(revision, &rel_path)?,
+                    path,
+                    mode: None,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.restore_contents(contents, revision, dry_run)
+    }
+
+    /// 将contents写入源文件，revision只用于提示与safety commit
+    pub(crate) fn restore_contents(
+        &self,
+        contents: Vec<RestoreContent>,
+        revision: &str,
+        dry_run: bool,
+    ) -> io::Result<Vec<RestoreChange>> {
+        let mut changes = vec![];
+        for content in contents {
+            let diff = if content.path.exists() {
+                let mut diff = storage::unified_diff(
+                    &fs::read(&content.path)?,
+                    &content.content,
+                    &content.path.display().to_string(),
+                    revision,
+                );
+                let current_mode = fs::metadata(&content.path)?.permissions().mode() & 0o7777;
+                match content.mode {
+                    Some(mode) if mode != current_mode => {
+                        diff.push_str(&format!("mode {:o} => {:o}\n", current_mode, mode))
+                    }
+                    _ if diff.is_empty() => continue,
+                    _ => {}
+                }
+                Some(diff)
+            } else {
+                None
+            };
+            changes.push((
+                RestoreChange {
+                    path: content.path.clone(),
+                    revision: revision.to_string(),
+                    diff,
+                },
+                content,
+            ));
+        }
+        if dry_run || changes.is_empty() {
+            return Ok(changes.into_iter().map(|(change, _)| change).collect());
+        }
+
+        // 回滚前保存当前状态
+        for (change, _) in &changes {
+            if change.path.is_file() {
+                self.hold(&change.path)?;
+            }
+        }
+        self.commit_message(&format!("safety commit before restoring to {}", revision))?;
+
+        for (change, content) in &changes {
+            self.write_to_source(&change.path, &content.content, content.mode)?;
+        }
+        Ok(changes.into_iter().map(|(change, _)| change).collect())
+    }
+
+    /// 获取config在revision时的所有备份文件，返回(源文件路径, 相对于备份目录的路径)
+    pub(crate) fn list_configuration_files(
+        &self,
+        config: &Configuration,
+        revision: &str,
+    ) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut files = vec![];
+        for from_path in config.from_paths.keys() {
+            let mapper = self.get_mapper(from_path);
+            let dir = self.to_relative_path(&mapper.to_backup_path(from_path, &config.name)?)?;
+            for rel_path in self.storage.list_files(revision, &[dir])? {
+                let origin_path = self.to_origin_path(mapper, &rel_path)?;
+                files.push((origin_path, rel_path));
+            }
+        }
+        Ok(files)
+    }
+
+    fn to_relative_path(&self, backup_path: &Path) -> io::Result<PathBuf> {
+        backup_path
+            .strip_prefix(&self.backup_base_path)
+            .map(Path::to_path_buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn to_origin_path(&self, mapper: &BackupPathMapper, rel_path: &Path) -> io::Result<PathBuf> {
+        mapper.to_origin_path(&self.backup_base_path.join(rel_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::tests::init_repo;
+    use crate::mapping::BackupLayout;
+    use crate::storage::StorageKind;
+    use std::env;
+
+    fn context(dir: &Path, src: &Path) -> BackupContext {
+        context_with_storage(dir, src, StorageKind::Git)
+    }
+
+    fn context_with_storage(dir: &Path, src: &Path, storage: StorageKind) -> BackupContext {
+        let backup = dir.join("backup");
+        match storage {
+            StorageKind::Git => init_repo(&backup),
+            StorageKind::Snapshot => {
+                let _ = fs::remove_dir_all(&backup);
+                fs::create_dir_all(&backup).unwrap();
+            }
+        }
+        let config = Configuration::for_test("test", &[src]);
+        BackupContext::new(vec![config], &backup, BackupLayout::Absolute, None, storage)
+    }
+
+    fn backup(context: &BackupContext, path: &Path, content: &str) {
+        fs::write(path, content).unwrap();
+        context.hold(path).unwrap();
+        context.commit(path).unwrap();
+    }
+
+    #[test]
+    fn restore_file_at_revision() {
+        let dir = env::temp_dir().join("auto_configuration_restore_at");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let context = context(&dir, &src);
+        let a = src.join("a.txt");
+        backup(&context, &a, "1\n");
+        backup(&context, &a, "2\n");
+        fs::write(&a, "3\n").unwrap();
+
+        let point = RestorePoint::Revision("HEAD~1".to_string());
+        let changes = context
+            .restore_at(std::slice::from_ref(&a), &point, true)
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        // 当前内容被删除，revision时的内容被写入
+        let diff = changes[0].diff.as_ref().unwrap();
+        assert!(diff.starts_with(&format!(
+            "--- {}\n+++ {}\n",
+            a.display(),
+            changes[0].revision
+        )));
+        assert!(diff.contains("-3\n+1\n"));
+        assert_eq!(fs::read_to_string(&a).unwrap(), "3\n");
+
+        let changes = context
+            .restore_at(std::slice::from_ref(&a), &point, false)
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "1\n");
+        // 回滚前的状态已被提交
+        let commits = context.log(&a).unwrap();
+        assert!(commits[0].message.starts_with("safety commit"));
+        assert_eq!(context.show(&a, "HEAD").unwrap(), b"3\n");
+
+        let none = src.join("none.txt");
+        assert!(context.restore_at(&[none], &point, true).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_configuration_at_time() {
+        let dir = env::temp_dir().join("auto_configuration_restore_configuration_at");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let context = context(&dir, &src);
+        backup(&context, &src.join("a.txt"), "a");
+        backup(&context, &src.join("b.txt"), "b");
+        fs::remove_file(src.join("a.txt")).unwrap();
+
+        let point = RestorePoint::Time("now".to_string());
+        let changes = context
+            .restore_configuration_at("test", &point, false)
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].diff, None);
+        assert_eq!(fs::read_to_string(src.join("a.txt")).unwrap(), "a");
+        assert!(context
+            .restore_configuration_at("none", &point, true)
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 在PATH为空的子进程中执行[restore_with_snapshot_storage_without_git]
+    #[test]
+    fn restore_with_snapshot_storage() {
+        let out = std::process::Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "--ignored",
+                "restore::tests::restore_with_snapshot_storage_without_git",
+            ])
+            .env("PATH", "")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(out.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
+
+    #[test]
+    #[ignore = "由restore_with_snapshot_storage在PATH为空时执行"]
+    fn restore_with_snapshot_storage_without_git() {
+        assert_eq!(crate::program::find_command("git"), None);
+        let dir = env::temp_dir().join("auto_configuration_restore_snapshot");
+        let src = dir.join("src");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&src).unwrap();
+        let context = context_with_storage(&dir, &src, StorageKind::Snapshot);
+        let a = src.join("a.txt");
+        backup(&context, &a, "1\n");
+        backup(&context, &a, "2\n");
+        assert_eq!(context.log(&a).unwrap().len(), 2);
+        assert!(context
+            .diff(&a, Some("HEAD~1"), None)
+            .unwrap()
+            .contains("-1\n+2\n"));
+
+        let point = RestorePoint::Revision("HEAD~1".to_string());
+        let changes = context
+            .restore_at(std::slice::from_ref(&a), &point, false)
+            .unwrap();
+        assert!(changes[0].diff.as_ref().unwrap().contains("-2\n+1\n"));
+        assert_eq!(fs::read_to_string(&a).unwrap(), "1\n");
+        // 不通过date命令解析时间
+        let point = RestorePoint::Time("now".to_string());
+        let changes = context
+            .restore_at(std::slice::from_ref(&a), &point, true)
+            .unwrap();
+        assert!(changes[0].diff.as_ref().unwrap().contains("-1\n+2\n"));
+        assert!(!dir.join("backup/.git").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
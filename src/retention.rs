@@ -0,0 +1,383 @@
+use crate::history::{self, Commit};
+use crate::storage::StorageKind;
+use crate::BackupContext;
+
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// 保存精简后历史的分支，当前分支不会被修改，原有的提交id仍可用于log与restore
+pub const RETENTION_BRANCH: &str = "retention";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// 超过keep_days的提交只保留每个snapshot周期内的最后一个
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetentionPolicy {
+    pub keep_days: u64,
+    #[serde(default)]
+    pub snapshot: SnapshotInterval,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotInterval {
+    #[default]
+    Daily,
+    Weekly,
+}
+
+impl RetentionPolicy {
+    /// 返回time所在的snapshot周期，在keep_days内时返回None表示需要保留
+    fn get_bucket(&self, time: u64, now: u64) -> Option<u64> {
+        if time + self.keep_days * SECONDS_PER_DAY > now {
+            return None;
+        }
+        let day = time / SECONDS_PER_DAY;
+        Some(match self.snapshot {
+            SnapshotInterval::Daily => day,
+            // 1970-01-01是周四，+3使每周从周一开始
+            SnapshotInterval::Weekly => (day + 3) / 7,
+        })
+    }
+}
+
+/// 精简历史的结果
+#[derive(Debug, Default)]
+pub struct RetentionReport {
+    /// 保留的提交，最早的在前
+    pub kept: Vec<Commit>,
+    /// 被合并的提交与合并到的提交id
+    pub squashed: Vec<(Commit, String)>,
+}
+
+struct LogEntry {
+    commit: Commit,
+    /// 提交原来的时区，如`+0800`
+    timezone: String,
+    tree: String,
+    files: Vec<PathBuf>,
+}
+
+impl BackupContext {
+    /// 按各configuration的retention策略精简备份历史，now为unix时间戳(秒)
+    ///
+    /// 保留的提交会以原时间与时区重新提交到[RETENTION_BRANCH]分支，被合并提交的修改
+    /// 包含在之后保留的提交中。每次都从当前分支的完整历史重建，相同的历史得到相同的
+    /// 提交id。dry_run时只返回报告
+    pub fn prune(&self, now: u64, dry_run: bool) -> io::Result<RetentionReport> {
+        if self.storage.get_kind() != StorageKind::Git {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "只有git存储支持按保留策略清理历史",
+            ));
+        }
+        let entries = read_log(&self.backup_base_path)?;
+        let policies = self.get_retention_policies()?;
+
+        let mut keep = HashSet::new();
+        // 每个configuration每个周期内最后的提交
+        let mut latest = HashMap::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let mut unmanaged = entry.files.is_empty();
+            for file in &entry.files {
+                match policies.iter().position(|(dir, _)| file.starts_with(dir)) {
+                    Some(p) => match policies[p].1.get_bucket(entry.commit.time, now) {
+                        Some(bucket) => {
+                            latest.insert((p, bucket), i);
+                        }
+                        None => unmanaged = true,
+                    },
+                    // 没有retention策略的文件保留所有提交
+                    None => unmanaged = true,
+                }
+            }
+            if unmanaged {
+                keep.insert(i);
+            }
+        }
+        keep.extend(latest.values());
+        if !entries.is_empty() {
+            keep.insert(entries.len() - 1);
+        }
+
+        let mut report = RetentionReport::default();
+        let mut squashed = vec![];
+        for (i, entry) in entries.iter().enumerate() {
+            if keep.contains(&i) {
+                report.squashed.extend(
+                    squashed
+                        .drain(..)
+                        .map(|commit| (commit, entry.commit.id.clone())),
+                );
+                report.kept.push(entry.commit.clone());
+            } else {
+                squashed.push(entry.commit.clone());
+            }
+        }
+        if dry_run || report.squashed.is_empty() {
+            return Ok(report);
+        }
+
+        let mut parent: Option<String> = None;
+        for (i, entry) in entries.iter().enumerate() {
+            if !keep.contains(&i) {
+                continue;
+            }
+            let count = report
+                .squashed
+                .iter()
+                .filter(|(_, into)| *into == entry.commit.id)
+                .count();
+            let message = if count == 0 {
+                entry.commit.message.clone()
+            } else {
+                format!("{} (squashed {} commits)", entry.commit.message, count)
+            };
+            parent = Some(commit_tree(
+                &self.backup_base_path,
+                &entry.tree,
+                parent.as_deref(),
+                &message,
+                entry.commit.time,
+                &entry.timezone,
+            )?);
+        }
+        let tip = parent.expect("至少保留了最新的提交");
+        let head_tree = rev_parse(&self.backup_base_path, "HEAD^{tree}")?;
+        if rev_parse(&self.backup_base_path, &format!("{}^{{tree}}", tip))? != head_tree {
+            return Err(io::Error::other("精简后的历史与当前备份不一致"));
+        }
+        let branch_ref = format!("refs/heads/{}", RETENTION_BRANCH);
+        history::git(&self.backup_base_path, ["update-ref", &branch_ref, &tip])?;
+        Ok(report)
+    }
+
+    /// 获取各configuration备份目录(相对备份仓库)对应的策略
+    fn get_retention_policies(&self) -> io::Result<Vec<(PathBuf, RetentionPolicy)>> {
+        let mut policies = vec![];
+        for config in self.configurations.iter() {
+            let policy = match config.retention {
+                Some(policy) => policy,
+                None => continue,
+            };
+            for from_path in config.from_paths.keys() {
+                let backup_path = self
+                    .get_mapper(from_path)
+                    .to_backup_path(from_path, &config.name)?;
+                if let Ok(dir) = backup_path.strip_prefix(&self.backup_base_path) {
+                    policies.push((dir.to_path_buf(), policy));
+                }
+            }
+        }
+        Ok(policies)
+    }
+}
+
+/// 读取当前分支的first-parent历史，最早的在前
+fn read_log(repo_path: &Path) -> io::Result<Vec<LogEntry>> {
+    let out = history::git(
+        repo_path,
+        [
+            "-c",
+            "core.quotepath=off",
+            "log",
+            "--first-parent",
+            "--reverse",
+            "--name-only",
+            "--format=%x1e%H%x1f%at%x1f%ai%x1f%T%x1f%s",
+        ],
+    )?;
+    let out = String::from_utf8_lossy(&out);
+    let mut entries = vec![];
+    for record in out.split('\u{1e}').filter(|r| !r.trim().is_empty()) {
+        let mut lines = record.lines();
+        let fields = lines
+            .next()
+            .unwrap_or("")
+            .split('\u{1f}')
+            .collect::<Vec<_>>();
+        let (id, time, date, tree, message) = match fields.as_slice() {
+            [id, time, date, tree, message] => (id, time, date, tree, message),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("无法解析git log: {}", record),
+                ))
+            }
+        };
+        entries.push(LogEntry {
+            timezone: date.rsplit(' ').next().unwrap_or("+0000").to_string(),
+            commit: Commit {
+                id: id.to_string(),
+                time: time
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                date: date.to_string(),
+                message: message.to_string(),
+            },
+            tree: tree.to_string(),
+            files: lines
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+        });
+    }
+    Ok(entries)
+}
+
+fn rev_parse(repo_path: &Path, revision: &str) -> io::Result<String> {
+    let out = history::git(repo_path, ["rev-parse", "--verify", revision])?;
+    Ok(String::from_utf8_lossy(&out).trim().to_string())
+}
+
+/// 以time与timezone作为author与committer时间创建提交
+fn commit_tree(
+    repo_path: &Path,
+    tree: &str,
+    parent: Option<&str>,
+    message: &str,
+    time: u64,
+    timezone: &str,
+) -> io::Result<String> {
+    let date = format!("{} {}", time, timezone);
+    let mut command = Command::new("git");
+    command
+        .arg("commit-tree")
+        .arg(tree)
+        .arg("-m")
+        .arg(message)
+        .env("GIT_AUTHOR_DATE", &date)
+        .env("GIT_COMMITTER_DATE", &date)
+        .current_dir(repo_path);
+    if let Some(parent) = parent {
+        command.arg("-p").arg(parent);
+    }
+    let out = command.output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "git commit-tree error: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Configuration;
+    use std::env;
+    use std::fs;
+
+    const DAY: u64 = SECONDS_PER_DAY;
+    /// git不接受过小的时间戳，2020-09-14 00:00:00 UTC
+    const BASE: u64 = 18519 * DAY;
+
+    fn commit_at(repo: &Path, path: &str, content: &str, time: u64) {
+        commit_at_zone(repo, path, content, time, "+0000");
+    }
+
+    fn commit_at_zone(repo: &Path, path: &str, content: &str, time: u64, timezone: &str) {
+        let path = repo.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        history::git(repo, ["add", "-A"]).unwrap();
+        let date = format!("{} {}", time, timezone);
+        let status = Command::new("git")
+            .args(["commit", "-q", "-m", content])
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date)
+            .current_dir(repo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn weekly_bucket_starts_on_monday() {
+        let policy = RetentionPolicy {
+            keep_days: 1,
+            snapshot: SnapshotInterval::Weekly,
+        };
+        let now = 100 * DAY;
+        // 1970-01-04是周日，1970-01-05是周一
+        let (sunday, monday) = (3 * DAY, 4 * DAY);
+        assert_eq!(policy.get_bucket(0, now), policy.get_bucket(sunday, now));
+        assert_ne!(
+            policy.get_bucket(sunday, now),
+            policy.get_bucket(monday, now)
+        );
+        assert_eq!(policy.get_bucket(now - 1, now), None);
+    }
+
+    #[test]
+    fn prune_daily() {
+        let dir = env::temp_dir().join("auto_configuration_retention");
+        let backup = dir.join("backup");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+
+        let config = Configuration {
+            retention: Some(RetentionPolicy {
+                keep_days: 7,
+                snapshot: SnapshotInterval::Daily,
+            }),
+            ..Configuration::for_test("test", &[&src])
+        };
+        let context = BackupContext::for_test(vec![config], &backup);
+        let rel = context
+            .get_backup_relative_path(&src.join("a.txt"))
+            .unwrap();
+        let rel = rel.to_str().unwrap();
+
+        let now = BASE + 100 * DAY;
+        // 第1天3个提交，第2天2个，最近的2个在保留期内
+        let times = [1, 2, 3, DAY, DAY + 1].iter().map(|t| BASE + t);
+        for (i, time) in times.chain(vec![now - 2, now - 1]).enumerate() {
+            commit_at(&backup, rel, &i.to_string(), time);
+        }
+        commit_at_zone(&backup, rel, "tz", now - 1, "+0800");
+        // 没有策略的文件不会被合并
+        commit_at(&backup, "other.txt", "other", BASE + 4);
+
+        let report = context.prune(now, true).unwrap();
+        assert_eq!(report.squashed.len(), 3);
+        assert_eq!(report.kept.len(), 6);
+        assert_eq!(history::log(&backup, Path::new(rel)).unwrap().len(), 8);
+
+        let head = rev_parse(&backup, "HEAD").unwrap();
+        let report = context.prune(now, false).unwrap();
+        assert_eq!(report.squashed.len(), 3);
+        // 当前分支不变，原有的提交仍可用于restore
+        assert_eq!(rev_parse(&backup, "HEAD").unwrap(), head);
+        assert_eq!(history::log(&backup, Path::new(rel)).unwrap().len(), 8);
+        assert_eq!(
+            history::show(&backup, &report.squashed[0].0.id, Path::new(rel)).unwrap(),
+            b"0"
+        );
+
+        let retention = rev_parse(&backup, RETENTION_BRANCH).unwrap();
+        assert_eq!(
+            rev_parse(&backup, &format!("{}^{{tree}}", retention)).unwrap(),
+            rev_parse(&backup, "HEAD^{tree}").unwrap()
+        );
+        let out = history::git(
+            &backup,
+            ["log", "--format=%ai %s", RETENTION_BRANCH, "--", rel],
+        )
+        .unwrap();
+        let out = String::from_utf8_lossy(&out);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with("+0800 tz"));
+        assert!(lines[4].contains("squashed 2 commits"));
+        // 相同的历史重建得到相同的提交
+        context.prune(now, false).unwrap();
+        assert_eq!(rev_parse(&backup, RETENTION_BRANCH).unwrap(), retention);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,469 @@
+use crate::program;
+use crate::Configuration;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// systemd unit的名称
+pub const UNIT_NAME: &str = "auto-configuration.service";
+
+/// 状态更新的间隔，也是没有启用watchdog时事件循环的超时
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// system服务允许普通用户以root运行helper的sudoers规则
+const SUDOERS_PATH: &str = "/etc/sudoers.d/auto-configuration";
+
+/// `systemctl status`时查询的unit属性
+const STATUS_PROPERTIES: [&str; 5] = [
+    "LoadState",
+    "ActiveState",
+    "SubState",
+    "MainPID",
+    "StatusText",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServiceScope {
+    /// `systemctl --user`，用于备份`$HOME`下的配置
+    User,
+    /// 以普通用户运行，通过以root运行的helper读写`/etc`等目录
+    System,
+}
+
+impl ServiceScope {
+    /// from_paths都在`$HOME`下时为User，否则为System
+    pub fn detect(configurations: &[Configuration]) -> ServiceScope {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let in_home = configurations
+            .iter()
+            .flat_map(|config| config.from_paths.keys())
+            .all(|path| matches!(&home, Some(home) if path.starts_with(home)));
+        if in_home {
+            ServiceScope::User
+        } else {
+            ServiceScope::System
+        }
+    }
+
+    /// 解析`--user`或`--system`
+    pub fn parse(arg: &str) -> Option<ServiceScope> {
+        match arg {
+            "--user" => Some(ServiceScope::User),
+            "--system" => Some(ServiceScope::System),
+            _ => None,
+        }
+    }
+
+    /// unit文件的安装路径
+    pub fn get_unit_path(&self) -> io::Result<PathBuf> {
+        match self {
+            ServiceScope::User => {
+                let config_home = match env::var_os("XDG_CONFIG_HOME") {
+                    Some(dir) => PathBuf::from(dir),
+                    None => env::var_os("HOME")
+                        .map(|home| PathBuf::from(home).join(".config"))
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotFound, "环境变量HOME不存在")
+                        })?,
+                };
+                Ok(config_home.join("systemd/user").join(UNIT_NAME))
+            }
+            ServiceScope::System => Ok(Path::new("/etc/systemd/system").join(UNIT_NAME)),
+        }
+    }
+
+    fn systemctl(&self) -> Command {
+        let mut command = Command::new("systemctl");
+        if *self == ServiceScope::User {
+            command.arg("--user");
+        }
+        command
+    }
+}
+
+/// 生成以exe运行config_path的watch的unit，system服务以user运行
+pub fn generate_unit(scope: ServiceScope, exe: &Path, config_path: &Path, user: &str) -> String {
+    // user unit默认已在basic.target之后
+    let (after, user, wanted_by) = match scope {
+        ServiceScope::User => (String::new(), String::new(), "default.target"),
+        ServiceScope::System => (
+            "After=local-fs.target\n".to_string(),
+            format!("User={}\n", user),
+            "multi-user.target",
+        ),
+    };
+    format!(
+        "[Unit]
+Description=auto-configuration backup daemon
+{}
+[Service]
+Type=notify
+{}ExecStart={} -c {} watch
+WatchdogSec=60
+Restart=on-failure
+
+[Install]
+WantedBy={}
+",
+        after,
+        user,
+        quote_exec_arg(exe.as_os_str()),
+        quote_exec_arg(config_path.as_os_str()),
+        wanted_by
+    )
+}
+
+/// 按systemd的规则引用ExecStart的参数，`%`与`$`会被systemd展开，需要重复
+fn quote_exec_arg(arg: &OsStr) -> String {
+    let mut quoted = String::from("\"");
+    for chunk in arg.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' | '\\' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '%' => quoted.push_str("%%"),
+                '$' => quoted.push_str("$$"),
+                c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// system服务运行的用户：通过sudo执行时为原来的用户，不允许为root
+pub fn get_service_user() -> io::Result<String> {
+    env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .ok()
+        .filter(|user| !user.is_empty() && user != "root")
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "system服务需要以普通用户运行，请通过sudo执行",
+            )
+        })
+}
+
+/// 生成允许user免密码以root运行helper_command的sudoers规则
+///
+/// helper_command必须为`sudo [-n] <command> [args]`，command不是绝对路径时从PATH中查找
+pub fn generate_sudoers(user: &str, helper_command: &[String]) -> io::Result<String> {
+    let invalid = |message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("helper-command: {:?} {}", helper_command, message),
+        )
+    };
+    let command = match helper_command.split_first() {
+        Some((sudo, rest)) if Path::new(sudo).file_name() == Some(OsStr::new("sudo")) => rest,
+        _ => return Err(invalid("不是sudo命令")),
+    };
+    let command = command
+        .iter()
+        .skip_while(|arg| *arg == "-n" || *arg == "--non-interactive")
+        .collect::<Vec<_>>();
+    let (exe, args) = command.split_first().ok_or_else(|| invalid("没有命令"))?;
+    if exe.starts_with('-') {
+        return Err(invalid("只支持sudo的-n参数"));
+    }
+    let exe = if Path::new(exe).is_absolute() {
+        PathBuf::from(exe)
+    } else {
+        program::find_command(exe).ok_or_else(|| invalid("命令不存在"))?
+    };
+    let exe = exe.to_str().ok_or_else(|| invalid("命令路径不是UTF-8"))?;
+    let mut rule = format!("{} ALL=(root) NOPASSWD: {}", user, escape_sudoers(exe));
+    for arg in args {
+        if arg.is_empty() || arg.chars().any(char::is_whitespace) {
+            return Err(invalid("参数不能为空或包含空白字符"));
+        }
+        rule.push(' ');
+        rule.push_str(&escape_sudoers(arg));
+    }
+    rule.push('\n');
+    Ok(rule)
+}
+
+/// sudoers中`\\ , : =`需要以`\\`转义
+fn escape_sudoers(word: &str) -> String {
+    let mut escaped = String::new();
+    for c in word.chars() {
+        if matches!(c, '\\' | ',' | ':' | '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 写入unit文件并启用、启动服务，返回unit文件路径
+///
+/// system服务以[get_service_user]运行，需要helper_command，
+/// 同时写入允许该用户以root运行helper的sudoers规则。
+/// helper以root读写config_path中配置的路径，config_path需要满足[check_root_owned]
+pub fn install(
+    scope: ServiceScope,
+    config_path: &Path,
+    helper_command: Option<&[String]>,
+) -> io::Result<PathBuf> {
+    let exe = env::current_exe()?;
+    let config_path = fs::canonicalize(config_path)?;
+    let user = match scope {
+        ServiceScope::User => String::new(),
+        ServiceScope::System => {
+            let user = get_service_user()?;
+            check_root_owned(&config_path)?;
+            let helper_command = helper_command.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "system服务需要配置helper-command以读写没有权限的文件",
+                )
+            })?;
+            install_sudoers(&generate_sudoers(&user, helper_command)?)?;
+            user
+        }
+    };
+    let unit_path = scope.get_unit_path()?;
+    if let Some(parent) = unit_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&unit_path, generate_unit(scope, &exe, &config_path, &user))?;
+    check_status(scope.systemctl().arg("daemon-reload"))?;
+    check_status(scope.systemctl().args(["enable", "--now", UNIT_NAME]))?;
+    Ok(unit_path)
+}
+
+/// path及其所有上级目录都属于root且group、other不可写，否则普通用户可以修改配置，
+/// 通过helper以root读写任意文件
+fn check_root_owned(path: &Path) -> io::Result<()> {
+    for path in path.ancestors() {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "path: {} 不属于root或可被其它用户写入，system服务的配置文件需要移动到只有root可写的目录",
+                    path.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 通过`visudo -c`检查后写入[SUDOERS_PATH]，检查失败时不修改原有的规则
+fn install_sudoers(rule: &str) -> io::Result<()> {
+    // sudo不读取名称中含有`.`的文件
+    let tmp_path = PathBuf::from(format!("{}.tmp", SUDOERS_PATH));
+    fs::write(&tmp_path, rule)?;
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o440))?;
+    let res = check_status(Command::new("visudo").arg("-cf").arg(&tmp_path))
+        .and_then(|_| fs::rename(&tmp_path, SUDOERS_PATH));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/// 查询unit的状态，StatusText为daemon通过sd_notify报告的状态
+pub fn status(scope: ServiceScope) -> io::Result<BTreeMap<String, String>> {
+    let out = scope
+        .systemctl()
+        .args(["show", UNIT_NAME])
+        .arg(format!("--property={}", STATUS_PROPERTIES.join(",")))
+        .output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "systemctl error: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(parse_properties(&String::from_utf8_lossy(&out.stdout)))
+}
+
+fn check_status(command: &mut Command) -> io::Result<()> {
+    let status = command.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} 失败: code={:?}",
+            command,
+            status.code()
+        )))
+    }
+}
+
+/// 解析`systemctl show`的`key=value`
+fn parse_properties(out: &str) -> BTreeMap<String, String> {
+    out.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// 向`$NOTIFY_SOCKET`发送sd_notify状态，如`READY=1`。不是由systemd启动时返回false
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+    let bytes = socket.as_bytes();
+    // `@`开头的为abstract socket
+    let addr = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// systemd要求的watchdog ping间隔，没有启用watchdog时为None
+pub fn get_watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_string_lossy() != std::process::id().to_string() {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    // 在超时的一半时ping
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(path: &Path) -> Configuration {
+        Configuration::for_test("test", &[path])
+    }
+
+    #[test]
+    fn detect_scope_and_generate_unit() {
+        let home = PathBuf::from(env::var("HOME").unwrap());
+        assert_eq!(
+            ServiceScope::detect(&[config(&home.join(".zshrc"))]),
+            ServiceScope::User
+        );
+        assert_eq!(
+            ServiceScope::detect(&[
+                config(&home.join(".zshrc")),
+                config(Path::new("/etc/mysql"))
+            ]),
+            ServiceScope::System
+        );
+
+        let unit = generate_unit(
+            ServiceScope::System,
+            Path::new("/usr/bin/auto-configuration"),
+            Path::new("/etc/auto configuration/100%.toml"),
+            "alice",
+        );
+        assert!(unit.contains("Type=notify\nUser=alice\n"));
+        assert!(unit.contains(
+            "ExecStart=\"/usr/bin/auto-configuration\" -c \"/etc/auto configuration/100%%.toml\" watch\n"
+        ));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+        let unit = generate_unit(ServiceScope::User, Path::new("/a\"$b"), Path::new("/c"), "");
+        assert!(!unit.contains("User="));
+        assert!(unit.contains("ExecStart=\"/a\\\"$$b\" -c \"/c\" watch\n"));
+        assert_eq!(
+            ServiceScope::System.get_unit_path().unwrap(),
+            PathBuf::from("/etc/systemd/system/auto-configuration.service")
+        );
+    }
+
+    #[test]
+    fn sudoers_rule() {
+        let command = [
+            "sudo",
+            "-n",
+            "/usr/bin/auto-configuration",
+            "-c",
+            "/etc/a=b.toml",
+            "helper",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            generate_sudoers("alice", &command).unwrap(),
+            "alice ALL=(root) NOPASSWD: /usr/bin/auto-configuration -c /etc/a\\=b.toml helper\n"
+        );
+        let invalid = |command: &[&str]| {
+            let command = command
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>();
+            generate_sudoers("alice", &command).unwrap_err().kind()
+        };
+        assert_eq!(invalid(&["pkexec", "/bin/sh"]), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            invalid(&["sudo", "-u", "root", "/bin/sh"]),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            invalid(&["sudo", "/bin/sh", "a b"]),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn root_owned_config() {
+        assert!(check_root_owned(Path::new("/")).is_ok());
+        let dir = crate::temp::TempDir::new("service").unwrap();
+        let path = dir.write_file("configuration.toml", b"").unwrap();
+        // 临时目录可被所有用户写入
+        assert_eq!(
+            check_root_owned(&path).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn parse_show_output() {
+        let properties =
+            parse_properties("ActiveState=active\nSubState=running\nStatusText=holding 1 paths\n");
+        assert_eq!(properties["ActiveState"], "active");
+        assert_eq!(properties["StatusText"], "holding 1 paths");
+    }
+
+    #[test]
+    fn notify_to_socket() {
+        let path = env::temp_dir().join("auto_configuration_notify.sock");
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        let name = format!("auto_configuration_notify_{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        notify_socket(OsStr::new(&format!("@{}", name)), "WATCHDOG=1").unwrap();
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+        fs::remove_file(&path).unwrap();
+    }
+}
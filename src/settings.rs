@@ -0,0 +1,425 @@
+use crate::configuration::{PackageManager, PrivilegeStrategy};
+use crate::helper::PrivilegedHelper;
+use crate::hook::Hooks;
+use crate::machine::MachineProfile;
+use crate::mapping::BackupLayout;
+use crate::program::{DeclaredProgram, ProgramDefinition};
+use crate::retention::RetentionPolicy;
+use crate::storage::StorageKind;
+use crate::watch::WatchMode;
+use crate::{BackupContext, Configuration, Program};
+
+use notify::RecursiveMode;
+use regex::{Captures, Regex};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 默认的配置文件
+pub const CONFIGURATION_FILE: &str = "configuration.toml";
+
+/// configuration.toml的内容
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    pub program: ProgramSettings,
+    #[serde(default)]
+    pub backup: BackupSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProgramSettings {
+    pub backup_base_dir: String,
+    #[serde(default)]
+    pub layout: BackupLayout,
+    /// 是否将备份按机器划分到`hosts/<id>`下
+    #[serde(default)]
+    pub per_machine: bool,
+    /// 默认使用hostname
+    pub machine_id: Option<String>,
+    /// 备份历史的存储方式
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// 以root启动helper的命令，如`["sudo", "-n", "auto-configuration", "helper"]`，
+    /// 不配置时没有权限的文件无法备份
+    pub helper_command: Option<Vec<String>>,
+    /// 单位：秒，watch时定期执行verify，不配置时不执行
+    pub verify_interval: Option<u64>,
+    /// 如`dnf`，不配置时根据`/etc/os-release`与存在的命令判断。只能为系统包管理器
+    #[serde(default, deserialize_with = "deserialize_system_manager")]
+    pub package_manager: Option<PackageManager>,
+    /// 不是root时安装系统包的提权方式
+    #[serde(default)]
+    pub privilege: PrivilegeStrategy,
+    /// `[program.<name>]`定义的程序，其中的未知字段会被拒绝
+    #[serde(flatten)]
+    pub programs: HashMap<String, ProgramDefinition>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BackupSettings {
+    #[serde(default)]
+    pub config: Vec<ConfigurationSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigurationSettings {
+    pub name: String,
+    /// 支持`$HOME`或`${HOME}`形式的环境变量
+    pub paths: Vec<String>,
+    #[serde(default = "default_recursive")]
+    pub recursive: bool,
+    /// 单位：秒
+    #[serde(default = "default_commit_duration")]
+    pub commit_duration: u64,
+    /// 是否保存到所有机器共享的common目录
+    #[serde(default)]
+    pub shared: bool,
+    /// 不配置时保留所有提交
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// native或poll，native失败时自动退回轮询
+    #[serde(default)]
+    pub watch_mode: WatchMode,
+    /// 单位：秒，轮询的间隔
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+}
+
+/// cargo等不是系统包管理器，只能用于单个程序的定义
+fn deserialize_system_manager<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<PackageManager>, D::Error> {
+    match Option::<PackageManager>::deserialize(deserializer)? {
+        Some(pm) if !pm.is_system() => Err(D::Error::custom(format!(
+            "package-manager: {} 不是系统包管理器，只能在程序的定义中设置",
+            pm.get_name()
+        ))),
+        pm => Ok(pm),
+    }
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+fn default_commit_duration() -> u64 {
+    10
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+impl Settings {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Settings::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        toml::from_str(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn get_backup_base_path(&self) -> io::Result<PathBuf> {
+        expand_env(&self.program.backup_base_dir).map(PathBuf::from)
+    }
+
+    pub fn get_machine_profile(&self) -> io::Result<Option<MachineProfile>> {
+        if !self.program.per_machine {
+            return Ok(None);
+        }
+        match &self.program.machine_id {
+            Some(id) => MachineProfile::new(id),
+            None => MachineProfile::from_hostname(),
+        }
+        .map(Some)
+    }
+
+    pub fn get_configurations(&self) -> io::Result<Vec<Configuration>> {
+        self.backup
+            .config
+            .iter()
+            .map(|config| config.to_configuration())
+            .collect()
+    }
+
+    /// 配置的或检测到的包管理器
+    pub fn get_package_manager(&self) -> io::Result<PackageManager> {
+        match self.program.package_manager {
+            Some(pm) => Ok(pm),
+            None => PackageManager::detect(),
+        }
+    }
+
+    /// `[program.<name>]`定义的程序，按名称排序
+    pub fn get_programs(&self) -> io::Result<Vec<Box<dyn Program>>> {
+        let mut names = self.program.programs.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut programs: Vec<Box<dyn Program>> = vec![];
+        // 只在有程序使用时检测一次
+        let mut default_pm = None;
+        for name in names {
+            let definition = &self.program.programs[name];
+            let pm = match (definition.package_manager, default_pm) {
+                (Some(pm), _) | (None, Some(pm)) => pm,
+                (None, None) => *default_pm.insert(self.get_package_manager()?),
+            };
+            programs.push(Box::new(DeclaredProgram::new(name, definition, pm)?));
+        }
+        Ok(programs)
+    }
+
+    pub fn to_context(&self) -> io::Result<BackupContext> {
+        let mut context = BackupContext::new(
+            self.get_configurations()?,
+            &self.get_backup_base_path()?,
+            self.program.layout,
+            self.get_machine_profile()?,
+            self.program.storage,
+        );
+        if let Some(command) = &self.program.helper_command {
+            context.set_helper(PrivilegedHelper::new(command.clone())?);
+        }
+        // 备份不依赖包管理器，没有时只是不记录文件所属的包
+        context.package_manager = match self.get_package_manager() {
+            Ok(pm) => pm,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => PackageManager::Other,
+            Err(e) => return Err(e),
+        };
+        context.privilege = self.program.privilege;
+        Ok(context)
+    }
+}
+
+impl ConfigurationSettings {
+    pub fn to_configuration(&self) -> io::Result<Configuration> {
+        let mode = if self.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        let mut from_paths = HashMap::new();
+        for path in &self.paths {
+            from_paths.insert(PathBuf::from(expand_env(path)?), mode);
+        }
+        Ok(Configuration {
+            from_paths,
+            commit_duration: Duration::from_secs(self.commit_duration),
+            name: self.name.clone(),
+            shared: self.shared,
+            retention: self.retention,
+            hooks: self.hooks.clone(),
+            watch_mode: self.watch_mode,
+            poll_interval: Duration::from_secs(self.poll_interval),
+        })
+    }
+}
+
+/// 替换s中的`$NAME`与`${NAME}`为环境变量的值，不存在的变量返回ErrorKind::NotFound
+pub fn expand_env(s: &str) -> io::Result<String> {
+    let reg = Regex::new(r"\$\{(\w+)\}|\$(\w+)").unwrap();
+    let mut err = None;
+    let expanded = reg.replace_all(s, |cap: &Captures| {
+        let name = cap.get(1).or_else(|| cap.get(2)).unwrap().as_str();
+        env::var(name).unwrap_or_else(|_| {
+            err = Some(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("环境变量{}不存在: {}", name, s),
+            ));
+            String::new()
+        })
+    });
+    match err {
+        Some(e) => Err(e),
+        None => Ok(expanded.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hook::HookFailurePolicy;
+    use crate::retention::SnapshotInterval;
+
+    #[test]
+    fn parse_settings() {
+        let settings = Settings::parse(
+            r#"
+[program]
+backup-base-dir = "./backup"
+layout = "home-relative"
+per-machine = true
+machine-id = "desktop"
+storage = "snapshot"
+helper-command = ["sudo", "-n", "auto-configuration", "helper"]
+verify-interval = 3600
+package-manager = "zypper"
+privilege = "sudo-n"
+
+[[backup.config]]
+name = "mysql"
+paths = ["/etc/mysql/my.cnf.d", "$HOME/my.cnf"]
+
+[[backup.config]]
+name = "zsh"
+paths = ["${HOME}/.zshrc"]
+recursive = false
+shared = true
+watch-mode = "poll"
+poll-interval = 30
+
+[backup.config.retention]
+keep-days = 30
+snapshot = "weekly"
+
+[[backup.config.hooks.pre-commit]]
+command = "zsh -n $AUTO_CONFIGURATION_PATH"
+timeout = 5
+
+[[backup.config.hooks.post-commit]]
+command = "echo done"
+on-failure = "ignore"
+
+[program.zsh]
+command = "zsh"
+dependencies = ["git"]
+config-files = ["$HOME/.zshrc"]
+post-install = [{ command = "chsh -s /bin/zsh", unless = "test \"$SHELL\" = /bin/zsh" }]
+plugins = [{ url = "https://github.com/ohmyzsh/ohmyzsh.git", path = "$HOME/.oh-my-zsh" }]
+env = { ZSH = "$HOME/.oh-my-zsh" }
+
+[program.git]
+package-manager = "pacman"
+packages = ["git", "git-lfs"]
+
+[program.rust]
+command = "cargo"
+packages = { apt-get = ["cargo", "libssl-dev", "pkg-config"], zypper = ["cargo", "libopenssl-devel"] }
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.program.layout, BackupLayout::HomeRelative);
+        assert_eq!(settings.program.storage, StorageKind::Snapshot);
+        assert_eq!(settings.program.helper_command.as_ref().unwrap()[0], "sudo");
+        assert!(settings.to_context().is_ok());
+        assert_eq!(settings.program.verify_interval, Some(3600));
+        assert_eq!(
+            settings.get_package_manager().unwrap(),
+            PackageManager::Zypper
+        );
+        assert_eq!(
+            settings.program.privilege,
+            PrivilegeStrategy::SudoNonInteractive
+        );
+        let home = PathBuf::from(env::var("HOME").unwrap());
+        let zsh = &settings.program.programs["zsh"];
+        assert_eq!(zsh.dependencies, vec!["git"]);
+        assert_eq!(zsh.plugins[0].path, "$HOME/.oh-my-zsh");
+        assert_eq!(zsh.env["ZSH"], "$HOME/.oh-my-zsh");
+        let programs = settings.get_programs().unwrap();
+        assert_eq!(programs.len(), 3);
+        assert_eq!(programs[0].get_name(), "git");
+        assert_eq!(programs[0].get_package_manager(), &PackageManager::Pacman);
+        assert_eq!(programs[0].get_package_names(), vec!["git", "git-lfs"]);
+        assert_eq!(
+            programs[1].get_package_names(),
+            vec!["cargo", "libopenssl-devel"]
+        );
+        assert_eq!(programs[2].get_package_manager(), &PackageManager::Zypper);
+        assert_eq!(programs[2].get_package_names(), vec!["zsh"]);
+        assert_eq!(
+            programs[2].get_configuration_paths(),
+            vec![home.join(".zshrc")]
+        );
+        assert_eq!(
+            settings.get_machine_profile().unwrap().unwrap().get_id(),
+            "desktop"
+        );
+        let configs = settings.get_configurations().unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(
+            configs[0].from_paths.get(&home.join("my.cnf")),
+            Some(&RecursiveMode::Recursive)
+        );
+        assert_eq!(configs[0].commit_duration, Duration::from_secs(10));
+        assert!(configs[1].shared);
+        assert_eq!(configs[0].watch_mode, WatchMode::Native);
+        assert_eq!(configs[0].poll_interval, Duration::from_secs(5));
+        assert_eq!(configs[1].watch_mode, WatchMode::Poll);
+        assert_eq!(configs[1].poll_interval, Duration::from_secs(30));
+        assert_eq!(configs[0].retention, None);
+        assert_eq!(
+            configs[1].retention,
+            Some(RetentionPolicy {
+                keep_days: 30,
+                snapshot: SnapshotInterval::Weekly
+            })
+        );
+        assert!(configs[0].hooks.pre_commit.is_empty());
+        let hooks = &configs[1].hooks;
+        assert_eq!(hooks.pre_commit[0].timeout, 5);
+        assert_eq!(
+            hooks.pre_commit[0].on_failure,
+            HookFailurePolicy::SkipCommit
+        );
+        assert_eq!(hooks.post_commit[0].timeout, 30);
+        assert_eq!(hooks.post_commit[0].on_failure, HookFailurePolicy::Ignore);
+        assert_eq!(
+            configs[1].from_paths.get(&home.join(".zshrc")),
+            Some(&RecursiveMode::NonRecursive)
+        );
+    }
+
+    #[test]
+    fn default_settings() {
+        let settings = Settings::parse("[program]\nbackup-base-dir = \"./backup\"").unwrap();
+        assert_eq!(settings.program.layout, BackupLayout::Absolute);
+        assert_eq!(settings.program.storage, StorageKind::Git);
+        assert!(settings.get_machine_profile().unwrap().is_none());
+        assert!(settings.backup.config.is_empty());
+    }
+
+    #[test]
+    fn reject_non_system_package_manager() {
+        let err = Settings::parse(
+            "[program]\nbackup-base-dir = \"./backup\"\npackage-manager = \"cargo\"",
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("cargo 不是系统包管理器"));
+    }
+
+    #[test]
+    fn reject_unknown_program_field() {
+        let err = Settings::parse(
+            "[program]\nbackup-base-dir = \"./backup\"\n[program.zsh]\npakages = [\"zsh\"]",
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("pakages"));
+        // 拼错的[program]配置被当作程序定义解析，同样被拒绝
+        let err =
+            Settings::parse("[program]\nbackup-base-dir = \"./backup\"\nverify-intervall = 60")
+                .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn expand_none_env() {
+        let res = expand_env("$_NONE_ENV_TEST/a");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn load_repository_configuration() {
+        assert!(Settings::load(Path::new(CONFIGURATION_FILE)).is_ok());
+    }
+}
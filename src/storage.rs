@@ -0,0 +1,909 @@
+use crate::history::{self, Commit};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// snapshot存储所在的目录：`$base/.snapshots`
+pub const SNAPSHOTS_DIR: &str = ".snapshots";
+
+/// 备份目录中不属于备份文件的目录
+pub const IGNORED_DIRS: [&str; 2] = [".git", SNAPSHOTS_DIR];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageKind {
+    /// 使用git仓库保存历史，需要git在PATH中
+    #[default]
+    Git,
+    /// 按内容hash去重的快照目录，每个快照一个manifest
+    Snapshot,
+}
+
+/// 备份历史的存储
+///
+/// hold时备份文件被复制到base_path下，commit时将base_path下的所有备份文件保存为一个新版本。
+/// revision为各实现中版本的id，都支持`HEAD`与`HEAD~n`
+pub trait Storage: Send + Sync {
+    fn get_kind(&self) -> StorageKind;
+
+    /// 保存base_path下所有备份文件，没有修改时返回false
+    fn commit(&self, message: &str) -> io::Result<bool>;
+
+    /// 将revision解析为完整的id
+    fn resolve(&self, revision: &str) -> io::Result<String>;
+
+    /// 获取time之前(含)的最后一个版本，time支持如`yesterday 18:00`的格式
+    fn revision_before(&self, time: &str) -> io::Result<String>;
+
+    /// 获取修改过path的版本，最新的在前。path为相对于base_path的备份路径
+    fn log(&self, path: &Path) -> io::Result<Vec<Commit>>;
+
+    /// 获取path在revision时的内容
+    fn show(&self, revision: &str, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// 获取path在revision时的权限位
+    ///
+    /// git只记录可执行位，返回0o755或0o644；snapshot记录提交时的0o777权限位
+    fn get_mode(&self, revision: &str, path: &Path) -> io::Result<u32>;
+
+    /// 获取revision时dirs下的所有文件，dirs为空时返回所有文件
+    fn list_files(&self, revision: &str, dirs: &[PathBuf]) -> io::Result<Vec<PathBuf>>;
+
+    /// 检查存储本身的完整性，返回发现的问题
+    fn verify(&self) -> io::Result<Vec<String>>;
+
+    /// 比较path在两个revision之间的差异
+    fn diff_revisions(&self, from: &str, to: &str, path: &Path) -> io::Result<String> {
+        let old = self.show(from, path)?;
+        let new = self.show(to, path)?;
+        Ok(unified_diff(&old, &new, from, to))
+    }
+
+    /// 比较live_path的当前内容与path在revision时的内容，没有差异时为空
+    fn diff_live(&self, revision: &str, path: &Path, live_path: &Path) -> io::Result<String> {
+        diff_content(&self.show(revision, path)?, revision, live_path)
+    }
+}
+
+pub fn new_storage(kind: StorageKind, base_path: &Path) -> Box<dyn Storage> {
+    match kind {
+        StorageKind::Git => Box::new(GitStorage::new(base_path)),
+        StorageKind::Snapshot => Box::new(SnapshotStorage::new(base_path)),
+    }
+}
+
+/// 内容的sha256，小写十六进制
+pub fn hash_content(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// 比较old与live_path的当前内容，没有差异时为空
+pub fn diff_content(old: &[u8], name: &str, live_path: &Path) -> io::Result<String> {
+    let new = fs::read(live_path)?;
+    Ok(unified_diff(
+        old,
+        &new,
+        name,
+        &live_path.display().to_string(),
+    ))
+}
+
+/// `diff -u`格式的差异，在进程内计算，不依赖git或diff命令
+///
+/// 任意一侧不是UTF-8时只返回`Binary files ... differ`
+pub fn unified_diff(old: &[u8], new: &[u8], old_name: &str, new_name: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    let (old, new) = match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => (old, new),
+        _ => return format!("Binary files {} and {} differ\n", old_name, new_name),
+    };
+    let old = old.split_inclusive('\n').collect::<Vec<_>>();
+    let new = new.split_inclusive('\n').collect::<Vec<_>>();
+    let ops = diff_lines(&old, &new);
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, DiffOp::Equal(..)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let mut i = 0;
+    while i < changes.len() {
+        // 间隔不超过两倍上下文的修改合并到一个hunk
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1] - changes[j] <= 2 * DIFF_CONTEXT {
+            j += 1;
+        }
+        let start = changes[i].saturating_sub(DIFF_CONTEXT);
+        let end = (changes[j] + DIFF_CONTEXT + 1).min(ops.len());
+        push_hunk(&mut out, &ops[start..end], &old, &new);
+        i = j + 1;
+    }
+    out
+}
+
+/// 差异两侧保留的上下文行数
+const DIFF_CONTEXT: usize = 3;
+
+/// 超过时不计算最长公共子序列，中间部分整体作为删除与添加
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// 编辑操作，保存行在old与new中的下标
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// 先去掉相同的前缀与后缀，再按最长公共子序列计算编辑操作
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffOp> {
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut ops = (0..prefix).map(|i| DiffOp::Equal(i, i)).collect::<Vec<_>>();
+    if a.len().saturating_mul(b.len()) > MAX_DIFF_CELLS {
+        ops.extend((0..a.len()).map(|i| DiffOp::Delete(prefix + i)));
+        ops.extend((0..b.len()).map(|j| DiffOp::Insert(prefix + j)));
+    } else {
+        // lcs[i][j]为a[i..]与b[j..]的最长公共子序列长度
+        let width = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * width + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push(DiffOp::Equal(prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if j == b.len()
+                || (i < a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+            {
+                ops.push(DiffOp::Delete(prefix + i));
+                i += 1;
+            } else {
+                ops.push(DiffOp::Insert(prefix + j));
+                j += 1;
+            }
+        }
+    }
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+    ops.extend((0..suffix).map(|k| DiffOp::Equal(old_end + k, new_end + k)));
+    ops
+}
+
+fn push_hunk(out: &mut String, ops: &[DiffOp], old: &[&str], new: &[&str]) {
+    // hunk之前的行数
+    let (old_before, new_before) = match ops[0] {
+        DiffOp::Equal(i, j) => (i, j),
+        DiffOp::Delete(i) => (i, next_new_index(ops)),
+        DiffOp::Insert(j) => (next_old_index(ops), j),
+    };
+    let old_count = ops
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Insert(_)))
+        .count();
+    let new_count = ops
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Delete(_)))
+        .count();
+    out.push_str(&format!(
+        "@@ -{} +{} @@\n",
+        hunk_range(old_before, old_count),
+        hunk_range(new_before, new_count)
+    ));
+    for op in ops {
+        let (prefix, line) = match *op {
+            DiffOp::Equal(i, _) => (' ', old[i]),
+            DiffOp::Delete(i) => ('-', old[i]),
+            DiffOp::Insert(j) => ('+', new[j]),
+        };
+        out.push(prefix);
+        out.push_str(line);
+        if !line.ends_with('\n') {
+            out.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// 第一个删除操作之后的第一个new行的下标
+fn next_new_index(ops: &[DiffOp]) -> usize {
+    ops.iter()
+        .find_map(|op| match *op {
+            DiffOp::Equal(_, j) | DiffOp::Insert(j) => Some(j),
+            DiffOp::Delete(_) => None,
+        })
+        .unwrap_or(0)
+}
+
+fn next_old_index(ops: &[DiffOp]) -> usize {
+    ops.iter()
+        .find_map(|op| match *op {
+            DiffOp::Equal(i, _) | DiffOp::Delete(i) => Some(i),
+            DiffOp::Insert(_) => None,
+        })
+        .unwrap_or(0)
+}
+
+/// 与diff -u相同：只有一行时省略行数，没有行时为之前的行号
+fn hunk_range(before: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, count),
+    }
+}
+
+pub struct GitStorage {
+    repo_path: PathBuf,
+}
+
+impl GitStorage {
+    pub fn new(repo_path: &Path) -> Self {
+        GitStorage {
+            repo_path: repo_path.to_path_buf(),
+        }
+    }
+}
+
+impl Storage for GitStorage {
+    fn get_kind(&self) -> StorageKind {
+        StorageKind::Git
+    }
+
+    fn commit(&self, message: &str) -> io::Result<bool> {
+        history::commit_all(&self.repo_path, message)
+    }
+
+    fn resolve(&self, revision: &str) -> io::Result<String> {
+        let object = format!("{}^{{commit}}", revision);
+        let out = history::git(&self.repo_path, ["rev-parse", "--verify", &object])?;
+        Ok(String::from_utf8_lossy(&out).trim().to_string())
+    }
+
+    fn revision_before(&self, time: &str) -> io::Result<String> {
+        history::revision_before(&self.repo_path, time)
+    }
+
+    fn log(&self, path: &Path) -> io::Result<Vec<Commit>> {
+        history::log(&self.repo_path, path)
+    }
+
+    fn show(&self, revision: &str, path: &Path) -> io::Result<Vec<u8>> {
+        history::show(&self.repo_path, revision, path)
+    }
+
+    fn get_mode(&self, revision: &str, path: &Path) -> io::Result<u32> {
+        let tree = format!("{}^{{tree}}", self.resolve(revision)?);
+        let out = history::git(
+            &self.repo_path,
+            [
+                OsStr::new("ls-tree"),
+                OsStr::new(&tree),
+                OsStr::new("--"),
+                path.as_os_str(),
+            ],
+        )?;
+        match String::from_utf8_lossy(&out).split_whitespace().next() {
+            Some("100755") => Ok(0o755),
+            Some(_) => Ok(0o644),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("path: {} 在{}中不存在", path.display(), revision),
+            )),
+        }
+    }
+
+    fn list_files(&self, revision: &str, dirs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        history::list_files(&self.repo_path, revision, dirs)
+    }
+
+    fn diff_revisions(&self, from: &str, to: &str, path: &Path) -> io::Result<String> {
+        history::diff_revisions(&self.repo_path, from, to, path)
+    }
+
+    fn diff_live(&self, revision: &str, path: &Path, live_path: &Path) -> io::Result<String> {
+        history::diff_live(&self.repo_path, revision, path, live_path)
+    }
+
+    fn verify(&self) -> io::Result<Vec<String>> {
+        let out = Command::new("git")
+            .args(["fsck", "--no-progress", "--no-dangling"])
+            .current_dir(&self.repo_path)
+            .output()?;
+        if out.status.success() {
+            return Ok(vec![]);
+        }
+        let mut issues = String::from_utf8_lossy(&out.stderr).to_string();
+        issues.push_str(&String::from_utf8_lossy(&out.stdout));
+        Ok(issues
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// 一个快照：所有备份文件的路径与内容hash
+struct Manifest {
+    commit: Commit,
+    files: BTreeMap<PathBuf, ManifestFile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ManifestFile {
+    hash: String,
+    /// 0o777权限位，旧的manifest中没有时为[DEFAULT_SNAPSHOT_MODE]
+    mode: u32,
+}
+
+/// manifest中没有记录权限时使用的权限
+const DEFAULT_SNAPSHOT_MODE: u32 = 0o644;
+
+/// 不依赖git的快照存储
+///
+/// ```text
+/// $base/.snapshots/objects/<sha256>    文件内容，相同内容只保存一次
+/// $base/.snapshots/manifests/<id>      快照，id为`<unix时间>-<序号>`，按id排序即按时间排序
+/// ```
+///
+/// manifest的格式为`time <t>\nmessage <msg>\n\n`，之后每个文件为`<sha256> <八进制权限>\0<path>\0`
+pub struct SnapshotStorage {
+    base_path: PathBuf,
+}
+
+impl SnapshotStorage {
+    pub fn new(base_path: &Path) -> Self {
+        SnapshotStorage {
+            base_path: base_path.to_path_buf(),
+        }
+    }
+
+    fn get_objects_path(&self) -> PathBuf {
+        self.base_path.join(SNAPSHOTS_DIR).join("objects")
+    }
+
+    fn get_manifests_path(&self) -> PathBuf {
+        self.base_path.join(SNAPSHOTS_DIR).join("manifests")
+    }
+
+    /// 获取所有快照id，最早的在前
+    fn list_ids(&self) -> io::Result<Vec<String>> {
+        let path = self.get_manifests_path();
+        if !path.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut ids = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+            .filter(|id| !matches!(id, Ok(id) if id.ends_with(".tmp")))
+            .collect::<io::Result<Vec<_>>>()?;
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn read_manifest(&self, id: &str) -> io::Result<Manifest> {
+        let content = fs::read(self.get_manifests_path().join(id))?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("无法解析manifest: {}", id),
+            )
+        };
+        let split = content
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or_else(invalid)?;
+        let header = String::from_utf8_lossy(&content[..split]);
+        let (mut time, mut message) = (None, String::new());
+        for line in header.lines() {
+            if let Some(t) = line.strip_prefix("time ") {
+                time = t.parse::<u64>().ok();
+            } else if let Some(m) = line.strip_prefix("message ") {
+                message = m.to_string();
+            }
+        }
+        let time = time.ok_or_else(invalid)?;
+
+        let mut files = BTreeMap::new();
+        let mut fields = content[split + 2..].split(|b| *b == 0);
+        while let (Some(entry), Some(path)) = (fields.next(), fields.next()) {
+            if entry.is_empty() {
+                break;
+            }
+            let entry = String::from_utf8_lossy(entry);
+            let mut entry = entry.splitn(2, ' ');
+            let hash = entry.next().unwrap_or("").to_string();
+            let mode = match entry.next() {
+                Some(mode) => u32::from_str_radix(mode, 8).map_err(|_| invalid())?,
+                None => DEFAULT_SNAPSHOT_MODE,
+            };
+            files.insert(
+                PathBuf::from(OsStr::from_bytes(path)),
+                ManifestFile { hash, mode },
+            );
+        }
+        Ok(Manifest {
+            commit: Commit {
+                id: id.to_string(),
+                time,
+                date: format_time(time),
+                message,
+            },
+            files,
+        })
+    }
+
+    /// 保存path的内容到objects中并返回hash与权限
+    fn store_object(&self, path: &Path) -> io::Result<ManifestFile> {
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+        let content = fs::read(path)?;
+        let hash = hash_content(&content);
+        let object_path = self.get_objects_path().join(&hash);
+        if !object_path.exists() {
+            fs::create_dir_all(self.get_objects_path())?;
+            // 先写入临时文件，避免中断时留下不完整的object
+            let tmp_path = object_path.with_extension("tmp");
+            fs::write(&tmp_path, content)?;
+            fs::rename(tmp_path, object_path)?;
+        }
+        Ok(ManifestFile { hash, mode })
+    }
+}
+
+impl Storage for SnapshotStorage {
+    fn get_kind(&self) -> StorageKind {
+        StorageKind::Snapshot
+    }
+
+    fn commit(&self, message: &str) -> io::Result<bool> {
+        let mut paths = vec![];
+        if self.base_path.is_dir() {
+            crate::list_stored_files(&self.base_path, &mut paths)?;
+        }
+        let mut files = BTreeMap::new();
+        for path in paths {
+            let rel_path = path
+                .strip_prefix(&self.base_path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                .to_path_buf();
+            files.insert(rel_path, self.store_object(&path)?);
+        }
+        if let Some(id) = self.list_ids()?.last() {
+            if self.read_manifest(id)?.files == files {
+                return Ok(false);
+            }
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs();
+        let mut content =
+            format!("time {}\nmessage {}\n\n", time, message.replace('\n', " ")).into_bytes();
+        for (path, file) in &files {
+            content.extend_from_slice(format!("{} {:o}", file.hash, file.mode).as_bytes());
+            content.push(0);
+            content.extend_from_slice(path.as_os_str().as_bytes());
+            content.push(0);
+        }
+        let id = format!("{:010}-{:06}", time, self.list_ids()?.len());
+        fs::create_dir_all(self.get_manifests_path())?;
+        let manifest_path = self.get_manifests_path().join(&id);
+        let tmp_path = manifest_path.with_extension("tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(tmp_path, manifest_path)?;
+        Ok(true)
+    }
+
+    fn resolve(&self, revision: &str) -> io::Result<String> {
+        let ids = self.list_ids()?;
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("snapshot: {} 不存在", revision),
+            )
+        };
+        if let Some(n) = revision.strip_prefix("HEAD") {
+            let n = match n.strip_prefix('~') {
+                Some(n) => n.parse::<usize>().map_err(|_| not_found())?,
+                None if n.is_empty() => 0,
+                None => return Err(not_found()),
+            };
+            return ids.iter().rev().nth(n).cloned().ok_or_else(not_found);
+        }
+        let mut matched = ids.iter().filter(|id| id.starts_with(revision));
+        match (matched.next(), matched.next()) {
+            (Some(id), None) => Ok(id.clone()),
+            (Some(_), Some(_)) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("snapshot: {} 不唯一", revision),
+            )),
+            _ => Err(not_found()),
+        }
+    }
+
+    fn revision_before(&self, time: &str) -> io::Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs();
+        let t = parse_time(time, now)?;
+        for id in self.list_ids()?.iter().rev() {
+            if self.read_manifest(id)?.commit.time <= t {
+                return Ok(id.clone());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} 之前没有快照", time),
+        ))
+    }
+
+    fn log(&self, path: &Path) -> io::Result<Vec<Commit>> {
+        let mut commits = vec![];
+        let mut prev: Vec<(PathBuf, ManifestFile)> = vec![];
+        for id in self.list_ids()? {
+            let manifest = self.read_manifest(&id)?;
+            let files = manifest
+                .files
+                .into_iter()
+                .filter(|(p, _)| p.starts_with(path))
+                .collect::<Vec<_>>();
+            if files != prev {
+                commits.push(manifest.commit);
+                prev = files;
+            }
+        }
+        commits.reverse();
+        Ok(commits)
+    }
+
+    fn show(&self, revision: &str, path: &Path) -> io::Result<Vec<u8>> {
+        let id = self.resolve(revision)?;
+        let manifest = self.read_manifest(&id)?;
+        let file = manifest.files.get(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("path: {} 在{}中不存在", path.display(), id),
+            )
+        })?;
+        fs::read(self.get_objects_path().join(&file.hash))
+    }
+
+    fn get_mode(&self, revision: &str, path: &Path) -> io::Result<u32> {
+        let id = self.resolve(revision)?;
+        let manifest = self.read_manifest(&id)?;
+        manifest
+            .files
+            .get(path)
+            .map(|file| file.mode)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("path: {} 在{}中不存在", path.display(), id),
+                )
+            })
+    }
+
+    fn list_files(&self, revision: &str, dirs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        let manifest = self.read_manifest(&self.resolve(revision)?)?;
+        Ok(manifest
+            .files
+            .into_keys()
+            .filter(|path| dirs.is_empty() || dirs.iter().any(|dir| path.starts_with(dir)))
+            .collect())
+    }
+
+    fn verify(&self) -> io::Result<Vec<String>> {
+        let mut issues = vec![];
+        let mut checked = HashSet::new();
+        for id in self.list_ids()? {
+            let manifest = match self.read_manifest(&id) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    issues.push(format!("manifest {}: {}", id, e));
+                    continue;
+                }
+            };
+            for (path, ManifestFile { hash, .. }) in &manifest.files {
+                if !checked.insert(hash.clone()) {
+                    continue;
+                }
+                match fs::read(self.get_objects_path().join(hash)) {
+                    Ok(content) if hash_content(&content) == *hash => {}
+                    Ok(_) => issues.push(format!("object {} 已损坏: {}", hash, path.display())),
+                    Err(e) => issues.push(format!("object {} ({}): {}", hash, path.display(), e)),
+                }
+            }
+        }
+        Ok(issues)
+    }
+}
+
+/// 格式化为`%F %T %z`的本地时间，如`2024-01-02 03:04:05 +0800`
+fn format_time(time: u64) -> String {
+    let tm = local_time(time);
+    let offset = tm.tm_gmtoff / 60;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+/// 将time解析为unix时间戳(秒)，now为当前时间。支持：
+///
+/// - `now`、`@<timestamp>`
+/// - `<n> (second|minute|hour|day|week)[s] ago`
+/// - 本地时间`<YYYY-MM-DD|today|yesterday> [HH:MM[:SS]]`，不指定时间时为0点
+fn parse_time(time: &str, now: u64) -> io::Result<u64> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("无法解析时间: {}", time),
+        )
+    };
+    let lower = time.trim().to_lowercase();
+    if lower == "now" {
+        return Ok(now);
+    }
+    if let Some(timestamp) = lower.strip_prefix('@') {
+        return timestamp.parse().map_err(|_| invalid());
+    }
+    let words = lower.split_whitespace().collect::<Vec<_>>();
+    if let [n, unit, "ago"] = words[..] {
+        let seconds = match unit.strip_suffix('s').unwrap_or(unit) {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        return n
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(seconds))
+            .and_then(|ago| now.checked_sub(ago))
+            .ok_or_else(invalid);
+    }
+    let (date, clock) = match words[..] {
+        [date] => (date, None),
+        [date, clock] => (date, Some(clock)),
+        _ => return Err(invalid()),
+    };
+    let mut tm = local_time(now);
+    match date {
+        "today" => {}
+        "yesterday" => tm.tm_mday -= 1,
+        _ => {
+            let [year, mon, mday] = parse_fields(date, '-').ok_or_else(invalid)?;
+            if !(1..=12).contains(&mon) || !(1..=31).contains(&mday) {
+                return Err(invalid());
+            }
+            tm.tm_year = year - 1900;
+            tm.tm_mon = mon - 1;
+            tm.tm_mday = mday;
+        }
+    }
+    let (hour, min, sec) = match clock {
+        None => (0, 0, 0),
+        Some(clock) => match (parse_fields(clock, ':'), parse_fields(clock, ':')) {
+            (Some([hour, min, sec]), _) => (hour, min, sec),
+            (None, Some([hour, min])) => (hour, min, 0),
+            _ => return Err(invalid()),
+        },
+    };
+    if hour > 23 || min > 59 || sec > 60 {
+        return Err(invalid());
+    }
+    tm.tm_hour = hour;
+    tm.tm_min = min;
+    tm.tm_sec = sec;
+    // 由mktime判断是否为夏令时
+    tm.tm_isdst = -1;
+    let timestamp = unsafe { libc::mktime(&mut tm) };
+    u64::try_from(timestamp).map_err(|_| invalid())
+}
+
+/// 将`2024-01-02`或`03:04`按sep分为N个非负整数
+fn parse_fields<const N: usize>(s: &str, sep: char) -> Option<[i32; N]> {
+    let mut fields = [0; N];
+    let mut parts = s.split(sep);
+    for field in fields.iter_mut() {
+        let part = parts.next()?;
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *field = part.parse().ok()?;
+    }
+    parts.next().is_none().then_some(fields)
+}
+
+/// time在当前时区的本地时间
+fn local_time(time: u64) -> libc::tm {
+    let time = time as libc::time_t;
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    tm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn snapshot_commit_and_read() {
+        let base = env::temp_dir().join("auto_configuration_snapshot_storage");
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("etc")).unwrap();
+        let storage = SnapshotStorage::new(&base);
+        assert!(storage.resolve("HEAD").is_err());
+
+        fs::write(base.join("etc/a.txt"), "1\n").unwrap();
+        fs::write(base.join("etc/b.txt"), "1\n").unwrap();
+        assert!(storage.commit("first").unwrap());
+        assert!(!storage.commit("nothing").unwrap());
+        // 相同内容只保存一次
+        assert_eq!(fs::read_dir(storage.get_objects_path()).unwrap().count(), 1);
+
+        // 同一秒内的快照也按提交顺序排列
+        fs::write(base.join("etc/a.txt"), "2\n").unwrap();
+        assert!(storage.commit("second").unwrap());
+        fs::write(base.join("etc/b.txt"), "2\n").unwrap();
+        assert!(storage.commit("third").unwrap());
+
+        let a = Path::new("etc/a.txt");
+        let commits = storage.log(a).unwrap();
+        assert_eq!(
+            commits
+                .iter()
+                .map(|c| c.message.as_str())
+                .collect::<Vec<_>>(),
+            vec!["second", "first"]
+        );
+        assert_eq!(storage.show("HEAD~2", a).unwrap(), b"1\n");
+        assert_eq!(storage.show(&commits[0].id, a).unwrap(), b"2\n");
+        assert!(storage.resolve("HEAD").unwrap().ends_with("-000002"));
+        assert_eq!(
+            storage.list_files("HEAD", &[PathBuf::from("etc")]).unwrap(),
+            vec![PathBuf::from("etc/a.txt"), PathBuf::from("etc/b.txt")]
+        );
+        assert_eq!(
+            storage.revision_before("now").unwrap(),
+            storage.resolve("HEAD").unwrap()
+        );
+        assert!(storage
+            .diff_revisions("HEAD~2", "HEAD", a)
+            .unwrap()
+            .contains("-1\n+2\n"));
+        assert!(storage.show("HEAD", Path::new("none")).is_err());
+        assert_eq!(storage.get_mode("HEAD", a).unwrap(), 0o644);
+        fs::set_permissions(base.join(a), fs::Permissions::from_mode(0o750)).unwrap();
+        assert!(storage.commit("mode").unwrap());
+        assert_eq!(storage.get_mode("HEAD", a).unwrap(), 0o750);
+        assert_eq!(storage.get_mode("HEAD~1", a).unwrap(), 0o644);
+
+        assert!(storage.verify().unwrap().is_empty());
+        let hash = hash_content(b"2\n");
+        fs::write(storage.get_objects_path().join(&hash), "3\n").unwrap();
+        assert_eq!(storage.verify().unwrap().len(), 1);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn unified_diff_hunks() {
+        let old = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+        let new = old.replacen("2\n", "two\n", 1).replace("18\n", "");
+        assert_eq!(
+            unified_diff(old.as_bytes(), new.as_bytes(), "a", "b"),
+            "--- a
++++ b
+@@ -1,5 +1,5 @@
+ 1
+-2
++two
+ 3
+ 4
+ 5
+@@ -15,6 +15,5 @@
+ 15
+ 16
+ 17
+-18
+ 19
+ 20
+"
+        );
+        assert_eq!(
+            unified_diff(b"x\ny", b"x\nz\n", "a", "b"),
+            "--- a\n+++ b\n@@ -1,2 +1,2 @@\n x\n-y\n\\ No newline at end of file\n+z\n"
+        );
+        assert_eq!(
+            unified_diff(b"", b"a\n", "a", "b"),
+            "--- a\n+++ b\n@@ -0,0 +1 @@\n+a\n"
+        );
+        assert_eq!(unified_diff(b"same", b"same", "a", "b"), "");
+        assert_eq!(
+            unified_diff(&[0xff], b"a", "a", "b"),
+            "Binary files a and b differ\n"
+        );
+    }
+
+    #[test]
+    fn parse_and_format_time() {
+        let now = 1_700_000_000;
+        assert_eq!(parse_time("now", now).unwrap(), now);
+        assert_eq!(parse_time("@100", now).unwrap(), 100);
+        assert_eq!(parse_time("2 days ago", now).unwrap(), now - 2 * 86400);
+        assert_eq!(parse_time("1 Hour ago", now).unwrap(), now - 3600);
+        // 本地时间与format_time互为逆操作
+        let formatted = format_time(now);
+        assert_eq!(parse_time(&formatted[..19], now).unwrap(), now);
+        assert_eq!(parse_time(&formatted[..16], now).unwrap(), now - now % 60);
+        let today = parse_time("today", now).unwrap();
+        assert_eq!(parse_time(&formatted[..10], now).unwrap(), today);
+        assert!(today <= now && now - today < 86400);
+        let yesterday = parse_time("yesterday 18:00", now).unwrap();
+        assert!(yesterday < today + 18 * 3600 && yesterday > today - 86400);
+        for time in [
+            "tomorrow",
+            "2024-13-01",
+            "2024-01-01 25:00",
+            "1 year ago",
+            "@x",
+        ] {
+            assert_eq!(
+                parse_time(time, now).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput,
+                "{}",
+                time
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 同一进程中创建的临时目录的序号
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// 创建目录时名称已被占用的最大重试次数
+const MAX_ATTEMPTS: usize = 100;
+
+/// 权限为0700的私有临时目录，drop时删除
+///
+/// 目录由mkdir独占创建，已存在(包括被预先放置的symlink)时换一个名称，
+/// 其中的文件只能由当前用户创建，不会跟随其他用户放置的symlink
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> io::Result<Self> {
+        for _ in 0..MAX_ATTEMPTS {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let path = env::temp_dir().join(format!(
+                "auto-configuration-{}-{}-{}-{:08x}",
+                std::process::id(),
+                name,
+                COUNTER.fetch_add(1, Ordering::SeqCst),
+                nanos
+            ));
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("无法创建临时目录: {}", name),
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 以O_EXCL创建目录下的新文件，name中除字母数字外的字符被替换为`_`，
+    /// 与已有文件重名时加上序号
+    pub fn write_file(&self, name: &str, content: &[u8]) -> io::Result<PathBuf> {
+        let name = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        let mut path = self.path.join(&name);
+        let mut i = 1;
+        loop {
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    file.write_all(content)?;
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && i < MAX_ATTEMPTS => {
+                    path = self.path.join(format!("{}-{}", name, i));
+                    i += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn private_temp_dir() {
+        let dir = TempDir::new("test").unwrap();
+        let path = dir.path().to_path_buf();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_ne!(TempDir::new("test").unwrap().path(), path);
+
+        // 不同的revision可能替换为相同的名称
+        let a = dir.write_file("HEAD~1", b"a").unwrap();
+        let b = dir.write_file("HEAD^1", b"b").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(fs::read(&a).unwrap(), b"a");
+        assert_eq!(fs::read(&b).unwrap(), b"b");
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
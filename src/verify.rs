@@ -0,0 +1,166 @@
+use crate::storage;
+use crate::BackupContext;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// hold之后这段时间内的备份文件可能尚未提交，不检查hash
+const PENDING_COMMIT_DURATION: Duration = Duration::from_secs(60);
+
+/// verify发现的问题
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyIssue {
+    /// 存储本身的问题，如`git fsck`的输出
+    Repository(String),
+    /// 源文件存在但没有备份
+    MissingBackup(PathBuf),
+    /// 备份文件的源文件不在任何configuration中
+    Orphan(PathBuf),
+    /// 备份文件与最新提交中的内容不一致
+    HashMismatch(PathBuf),
+    /// 无法列出from_path下的源文件，包含错误信息
+    Unlistable(PathBuf, String),
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyIssue::Repository(message) => write!(f, "repository: {}", message),
+            VerifyIssue::MissingBackup(path) => write!(f, "missing backup: {}", path.display()),
+            VerifyIssue::Orphan(path) => write!(f, "orphan: {}", path.display()),
+            VerifyIssue::HashMismatch(path) => write!(f, "hash mismatch: {}", path.display()),
+            VerifyIssue::Unlistable(path, message) => {
+                write!(f, "unlistable: {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl BackupContext {
+    /// 检查存储的完整性、所有源文件都有备份、没有孤立的备份文件且备份文件与最新提交一致
+    pub fn verify(&self) -> io::Result<Vec<VerifyIssue>> {
+        let mut issues = self
+            .storage
+            .verify()?
+            .into_iter()
+            .map(VerifyIssue::Repository)
+            .collect::<Vec<_>>();
+
+        for config in self.configurations.iter() {
+            for (from_path, mode) in &config.from_paths {
+                // 一个from_path无法列出时不影响其它检查
+                let files = match self.list_sources(from_path, *mode) {
+                    Ok(files) => files,
+                    Err(e) => {
+                        issues.push(VerifyIssue::Unlistable(from_path.clone(), e.to_string()));
+                        continue;
+                    }
+                };
+                for path in files {
+                    let backup_path = self.get_mapper(&path).to_backup_path(&path, &config.name)?;
+                    if !backup_path.is_file() {
+                        issues.push(VerifyIssue::MissingBackup(path));
+                    }
+                }
+            }
+        }
+
+        // 还没有提交时HEAD不存在
+        let committed = self
+            .storage
+            .list_files("HEAD", &[])
+            .map(|files| files.into_iter().collect::<HashSet<_>>())
+            .unwrap_or_default();
+        for mapper in std::iter::once(&self.mapper).chain(&self.common_mapper) {
+            let mut backup_paths = vec![];
+            if mapper.get_base_path().is_dir() {
+                crate::list_backup_files(mapper.get_base_path(), &mut backup_paths)?;
+            }
+            for backup_path in backup_paths {
+                let origin_path = mapper.to_origin_path(&backup_path)?;
+                if self.find_configuration(&origin_path).is_none() {
+                    issues.push(VerifyIssue::Orphan(backup_path));
+                } else if !self.is_pending(&origin_path)
+                    && !self.is_committed(&committed, &backup_path)?
+                {
+                    issues.push(VerifyIssue::HashMismatch(backup_path));
+                }
+            }
+        }
+        Ok(issues)
+    }
+
+    fn is_pending(&self, from_path: &Path) -> bool {
+        self.holding_paths
+            .lock()
+            .unwrap()
+            .get(from_path)
+            .map(|time| time.elapsed() < PENDING_COMMIT_DURATION)
+            .unwrap_or(false)
+    }
+
+    fn is_committed(&self, committed: &HashSet<PathBuf>, backup_path: &Path) -> io::Result<bool> {
+        let rel_path = backup_path
+            .strip_prefix(&self.backup_base_path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if !committed.contains(rel_path) {
+            return Ok(false);
+        }
+        Ok(storage::hash_content(&self.storage.show("HEAD", rel_path)?)
+            == storage::hash_content(&fs::read(backup_path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Configuration;
+    use std::env;
+
+    #[test]
+    fn verify_backup_tree() {
+        let dir = env::temp_dir().join("auto_configuration_verify");
+        let backup = dir.join("backup");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let context =
+            BackupContext::for_test(vec![Configuration::for_test("test", &[&src])], &backup);
+        let a = src.join("a.txt");
+        fs::write(&a, "a").unwrap();
+        context.hold(&a).unwrap();
+        context.commit(&a).unwrap();
+        assert_eq!(context.verify().unwrap(), vec![]);
+
+        let b = src.join("b.txt");
+        fs::write(&b, "b").unwrap();
+        let backup_a = context.get_backup_file_path(&a).unwrap();
+        // 刚hold的文件还未提交，不检查hash
+        context.hold(&a).unwrap();
+        fs::write(&backup_a, "changed").unwrap();
+        assert_eq!(
+            context.verify().unwrap(),
+            vec![VerifyIssue::MissingBackup(b.clone())]
+        );
+
+        context.holding_paths.lock().unwrap().clear();
+        let orphan = context
+            .get_backup_file_path(&dir.join("other/c.txt"))
+            .unwrap();
+        fs::write(&orphan, "c").unwrap();
+        let mut issues = context.verify().unwrap();
+        issues.sort_by_key(|issue| issue.to_string());
+        assert_eq!(
+            issues,
+            vec![
+                VerifyIssue::HashMismatch(backup_a),
+                VerifyIssue::MissingBackup(b),
+                VerifyIssue::Orphan(orphan),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
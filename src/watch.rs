@@ -0,0 +1,278 @@
+use crate::helper::PrivilegedHelper;
+use crate::reconcile::list_source_files;
+use crate::storage;
+
+use notify::{DebouncedEvent, RecursiveMode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// 无法收到inotify事件的文件系统，其下的路径自动使用轮询
+const POLL_FILESYSTEMS: [&str; 9] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "afs",
+    "ceph",
+    "glusterfs",
+];
+
+/// configuration监听文件修改的方式
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchMode {
+    /// 使用notify的inotify watcher，失败或位于网络文件系统时退回轮询
+    #[default]
+    Native,
+    /// 定时比较mtime与hash
+    Poll,
+}
+
+/// 轮询时记录的文件状态
+#[derive(Debug, Clone, PartialEq)]
+struct FileState {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: String,
+}
+
+/// 每interval扫描一次path，将修改以与notify watcher相同的事件发送到tx
+///
+/// mtime与大小都没有变化时不读取文件；hash不变的修改(如touch)不产生事件。
+/// 没有权限的目录与文件通过helper列出与读取。tx的接收端关闭后线程退出
+pub fn poll(
+    path: &Path,
+    mode: RecursiveMode,
+    interval: Duration,
+    helper: Option<Arc<PrivilegedHelper>>,
+    tx: Sender<DebouncedEvent>,
+) -> io::Result<JoinHandle<()>> {
+    let path = path.to_path_buf();
+    let mut states = scan(&path, mode, helper.as_deref(), &HashMap::new())?;
+    Ok(thread::spawn(move || loop {
+        thread::sleep(interval);
+        let current = match scan(&path, mode, helper.as_deref(), &states) {
+            Ok(current) => current,
+            Err(e) => {
+                eprintln!("{} poll error: {}", path.display(), e);
+                continue;
+            }
+        };
+        for event in diff_states(&states, &current) {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+        states = current;
+    }))
+}
+
+/// 扫描path下的文件，mtime与大小不变时沿用previous中的hash
+fn scan(
+    path: &Path,
+    mode: RecursiveMode,
+    helper: Option<&PrivilegedHelper>,
+    previous: &HashMap<PathBuf, FileState>,
+) -> io::Result<HashMap<PathBuf, FileState>> {
+    let entries = match (list_entries(path, mode), helper) {
+        (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => helper
+            .list(path, mode)?
+            .into_iter()
+            .map(|(file, modified, len)| (file, Some(modified), len))
+            .collect(),
+        (res, _) => res?,
+    };
+    let mut states = HashMap::new();
+    for (file, modified, len) in entries {
+        let hash = match previous.get(&file) {
+            Some(state) if state.modified == modified && state.len == len => state.hash.clone(),
+            _ => match read_file(&file, helper) {
+                Ok(content) => storage::hash_content(&content),
+                Err(_) => continue,
+            },
+        };
+        states.insert(
+            file,
+            FileState {
+                modified,
+                len,
+                hash,
+            },
+        );
+    }
+    Ok(states)
+}
+
+/// 列出path下的文件与其mtime、大小
+fn list_entries(
+    path: &Path,
+    mode: RecursiveMode,
+) -> io::Result<Vec<(PathBuf, Option<SystemTime>, u64)>> {
+    let mut files = vec![];
+    list_source_files(path, mode, &mut files)?;
+    Ok(files
+        .into_iter()
+        // 扫描期间被删除的文件在下次扫描时处理
+        .filter_map(|file| {
+            let metadata = fs::metadata(&file).ok()?;
+            Some((file, metadata.modified().ok(), metadata.len()))
+        })
+        .collect())
+}
+
+fn read_file(path: &Path, helper: Option<&PrivilegedHelper>) -> io::Result<Vec<u8>> {
+    match (fs::read(path), helper) {
+        (Err(e), Some(helper)) if e.kind() == io::ErrorKind::PermissionDenied => {
+            helper.read(path).map(|(content, _)| content)
+        }
+        (res, _) => res,
+    }
+}
+
+fn diff_states(
+    old: &HashMap<PathBuf, FileState>,
+    new: &HashMap<PathBuf, FileState>,
+) -> Vec<DebouncedEvent> {
+    let mut events = vec![];
+    for (path, state) in new {
+        match old.get(path) {
+            None => events.push(DebouncedEvent::Create(path.clone())),
+            Some(old) if old.hash != state.hash => events.push(DebouncedEvent::Write(path.clone())),
+            Some(_) => {}
+        }
+    }
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        events.push(DebouncedEvent::Remove(path.clone()));
+    }
+    events
+}
+
+/// path是否位于inotify无法感知远端修改的文件系统上
+pub fn needs_poll(path: &Path) -> bool {
+    // 挂载点可以不是UTF-8，按字节读取
+    match fs::read("/proc/self/mounts") {
+        Ok(mounts) => find_filesystem(&mounts, path)
+            .map(|fs_type| {
+                fs_type.starts_with("fuse") || POLL_FILESYSTEMS.contains(&fs_type.as_str())
+            })
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// 在`/proc/self/mounts`中找到包含path的最长挂载点的文件系统类型
+fn find_filesystem(mounts: &[u8], path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    mounts
+        .split(|b| *b == b'\n')
+        .filter_map(|line| {
+            let mut fields = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|field| !field.is_empty());
+            let mount_point = unescape_mount(fields.nth(1)?);
+            let fs_type = String::from_utf8_lossy(fields.next()?);
+            Some((PathBuf::from(mount_point), fs_type))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .map(|(_, fs_type)| fs_type.to_string())
+}
+
+/// 挂载点中的空白字符被转义为`\040`等八进制形式，按字节还原
+fn unescape_mount(bytes: &[u8]) -> OsString {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|oct| std::str::from_utf8(oct).ok())
+            .and_then(|oct| u8::from_str_radix(oct, 8).ok());
+        match code {
+            Some(code) => {
+                out.push(code);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    OsString::from_vec(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn poll_changes() {
+        let dir = env::temp_dir().join("auto_configuration_poll");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.conf");
+        fs::write(&a, "1").unwrap();
+
+        let (tx, rx) = channel();
+        poll(
+            &dir,
+            RecursiveMode::Recursive,
+            Duration::from_millis(50),
+            None,
+            tx,
+        )
+        .unwrap();
+        let timeout = Duration::from_secs(2);
+
+        let b = dir.join("b.conf");
+        fs::write(&b, "1").unwrap();
+        assert!(matches!(rx.recv_timeout(timeout), Ok(DebouncedEvent::Create(path)) if path == b));
+        // 内容不变的修改不产生事件
+        fs::write(&a, "1").unwrap();
+        fs::write(&b, "22").unwrap();
+        assert!(matches!(rx.recv_timeout(timeout), Ok(DebouncedEvent::Write(path)) if path == b));
+        fs::remove_file(&a).unwrap();
+        assert!(matches!(rx.recv_timeout(timeout), Ok(DebouncedEvent::Remove(path)) if path == a));
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_mount_filesystem() {
+        let mounts = b"/dev/sda1 / ext4 rw 0 0
+server:/export /mnt/nfs\\040share nfs4 rw 0 0
+sshfs#host: /mnt/remote fuse.sshfs rw 0 0
+";
+        assert_eq!(
+            find_filesystem(mounts, Path::new("/mnt/nfs share/a.conf")),
+            Some("nfs4".to_string())
+        );
+        assert_eq!(
+            find_filesystem(mounts, Path::new("/mnt/remote/a")),
+            Some("fuse.sshfs".to_string())
+        );
+        assert_eq!(
+            find_filesystem(mounts, Path::new("/mnt/nfs")),
+            Some("ext4".to_string())
+        );
+        // 非UTF-8的挂载点
+        assert_eq!(
+            unescape_mount(b"/mnt/a\\040\\377b"),
+            OsString::from_vec(b"/mnt/a \xffb".to_vec())
+        );
+    }
+}